@@ -1,16 +1,122 @@
-use phf::{phf_map, Map};
+use std::collections::{HashMap, HashSet};
 
+use uuid::Uuid;
+
+use crate::bio::User;
+use crate::config::Config;
 use crate::devices::bluetooth::BluetoothDevice;
+use crate::devices::transport::Transport;
 use crate::devices::*;
 
-pub fn appraise(device: bluer::Device) -> Option<Box<dyn BluetoothDevice<Target = bluer::Device>>> {
-    let address = device.address().to_string();
+pub type Constructor = fn(Box<dyn Transport>, User) -> Box<dyn BluetoothDevice>;
+
+struct Rule {
+    services:     &'static [Uuid],
+    names:        &'static [&'static str],
+    manufacturer: Option<u16>,
+    construct:    Constructor,
+}
+
+static RULES: &[Rule] = &[
+    Rule {
+        services:     &[miband::uuid::MI_SERVICES],
+        names:        &["MI1S"],
+        manufacturer: Some(miband::MANUFACTURER_ID),
+        construct:    miband::MiBand::<miband::OneS>::boxed,
+    },
+    Rule {
+        services:     &[miband::uuid::MI_SERVICES],
+        names:        &["MI", "MI1A"],
+        manufacturer: Some(miband::MANUFACTURER_ID),
+        construct:    miband::MiBand::<miband::One>::boxed,
+    },
+    Rule {
+        services:     &[capabilities::heartrate::uuid::HEART_RATE],
+        names:        &[],
+        manufacturer: None,
+        construct:    hrm::HeartRateMonitor::boxed,
+    },
+];
+
+struct Advertisement {
+    name:         Option<String>,
+    services:     HashSet<Uuid>,
+    manufacturer: HashMap<u16, Vec<u8>>,
+    rssi:         Option<i16>,
+}
 
-    BLUETOOTH_DEVICES.get(&address).map(|construct| construct(device))
+impl Advertisement {
+    async fn read(device: &bluer::Device) -> Result<Self, bluer::Error> {
+        Ok(Self {
+            name:         device.name().await?,
+            services:     device.uuids().await?.unwrap_or_default(),
+            manufacturer: device.manufacturer_data().await?.unwrap_or_default(),
+            rssi:         device.rssi().await?,
+        })
+    }
 }
 
-type Constructor = fn(bluer::Device) -> Box<dyn BluetoothDevice<Target = bluer::Device>>;
+impl Rule {
+    // Services and manufacturer are shared by whole families of devices, names tell models apart
+    fn matches(&self, adv: &Advertisement) -> bool {
+        let family = self.services.iter().any(|uuid| adv.services.contains(uuid))
+            || self.manufacturer.is_some_and(|id| adv.manufacturer.contains_key(&id));
+
+        let model = self.names.is_empty() || adv.name.as_deref().is_some_and(|name| self.names.contains(&name));
+
+        family && model
+    }
+}
+
+pub async fn appraise(device: &bluer::Device, config: &Config) -> Result<Option<Constructor>, bluer::Error> {
+    let adv = Advertisement::read(device).await?;
+
+    if let (Some(min), Some(rssi)) = (config.min_rssi, adv.rssi) {
+        if rssi < min {
+            return Ok(None)
+        }
+    }
 
-static BLUETOOTH_DEVICES: Map<&'static str, Constructor> = phf_map! {
-    "C8:0F:10:80:D0:AA" => miband::MiBand::<miband::OneS>::boxed
-};
+    Ok(RULES.iter()
+        .find(|rule| rule.matches(&adv))
+        .map(|rule| rule.construct))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertisement(name: &str, services: &[Uuid], manufacturer: Option<u16>) -> Advertisement {
+        Advertisement {
+            name:         Some(name.to_owned()),
+            services:     services.iter().copied().collect(),
+            manufacturer: manufacturer.into_iter().map(|id| (id, Vec::new())).collect(),
+            rssi:         None,
+        }
+    }
+
+    #[test]
+    fn miband_needs_name() {
+        let (miband, one) = (&RULES[0], &RULES[1]);
+
+        assert!(miband.matches(&advertisement("MI1S", &[miband::uuid::MI_SERVICES], None)));
+        assert!(one.matches(&advertisement("MI", &[], Some(miband::MANUFACTURER_ID))));
+
+        // 1 and 1A don't have heart rate sensor of 1S
+        assert!(!miband.matches(&advertisement("MI1A", &[miband::uuid::MI_SERVICES], None)));
+
+        // Later Huami bands
+        assert!(!miband.matches(&advertisement("MI Band 2", &[miband::uuid::MI_SERVICES], Some(miband::MANUFACTURER_ID))));
+        assert!(!miband.matches(&advertisement("Amazfit Bip", &[], Some(miband::MANUFACTURER_ID))));
+        // Name alone
+        assert!(!miband.matches(&advertisement("MI1S", &[], None)));
+    }
+
+    #[test]
+    fn heart_rate_by_service() {
+        let hrm = &RULES[2];
+
+        assert!(hrm.matches(&advertisement("Polar H10", &[capabilities::heartrate::uuid::HEART_RATE], None)));
+        assert!(!hrm.matches(&advertisement("Polar H10", &[], None)));
+    }
+}
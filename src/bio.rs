@@ -1,23 +1,76 @@
-#[derive(Clone, Copy)]
+use chrono::{Datelike, Local, NaiveDate};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::devices::capabilities::clock::ClockMode;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Sex {
     Male,
     Female
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Bio {
-    pub sex:    Sex,
-    pub age:    u8,
-    pub height: u8, // cm
-    pub weight: u8, // kg
+    pub sex:        Sex,
+    #[serde(deserialize_with = "date_from_str", serialize_with = "date_to_str")]
+    pub birth_date: NaiveDate,
+    pub height:     u8, // cm
+    pub weight:     u8, // kg
 }
 
 impl Default for Bio {
     fn default() -> Self {
         Self {
-            sex:    Sex::Male,
-            age:    14,
-            height: 162,
-            weight: 54,
+            sex:        Sex::Male,
+            birth_date: NaiveDate::from_ymd_opt(1990, 1, 1).unwrap(),
+            height:     175,
+            weight:     70,
         }
     }
 }
+
+impl Bio {
+    /// Full years as of today, devices only know the age and can't keep it up to date themselves.
+    pub fn age(&self) -> u8 {
+        let today = Local::now().date_naive();
+
+        let mut age = today.year() - self.birth_date.year();
+        if (today.month(), today.day()) < (self.birth_date.month(), self.birth_date.day()) {
+            age -= 1;
+        }
+
+        age.clamp(0, u8::MAX as i32) as u8
+    }
+}
+
+// Part of the user that can be changed by clients
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub alias: String,
+    pub bio:   Bio,
+}
+
+pub struct User {
+    pub id:    u32,
+    pub alias: String,
+    pub bio:   Bio,
+    pub clock: ClockMode,
+}
+
+fn date_from_str<'de, D>(d: D) -> Result<NaiveDate, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let s = String::deserialize(d)?;
+
+    NaiveDate::parse_from_str(&s, "%Y-%m-%d").map_err(de::Error::custom)
+}
+
+fn date_to_str<S>(date: &NaiveDate, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    s.collect_str(&date.format("%Y-%m-%d"))
+}
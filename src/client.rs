@@ -0,0 +1,211 @@
+use std::{collections::VecDeque, io, path::Path, time::Duration};
+
+use bluer::Address;
+use clap::Subcommand;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::{UnixStream, unix::{OwnedReadHalf, OwnedWriteHalf}};
+
+use crate::Error;
+
+// Measuring heart rate takes a while, anything longer means the device is gone
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Things to ask of a running daemon
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Battery level and status
+    Battery,
+    /// Steps made today
+    Steps {
+        /// Replace the count on the device
+        #[arg(long)]
+        set: Option<u32>,
+    },
+    /// Last known heart rate
+    Heartrate {
+        /// Measure it now instead
+        #[arg(long)]
+        measure: bool,
+    },
+    /// Make the device vibrate
+    Alert {
+        #[arg(value_parser = ["mild", "high"], default_value = "mild")]
+        level: String,
+    },
+    /// Print messages as they come
+    Watch {
+        /// Message types to print, e.g. battery or heartrate, everything when none is given
+        messages: Vec<String>,
+    },
+    /// Name and versions of the device
+    Info,
+}
+
+// Speaks JSON lines, events that come between answers are kept for later
+struct Client {
+    lines:   Lines<BufReader<OwnedReadHalf>>,
+    writer:  OwnedWriteHalf,
+    device:  Option<Address>,
+    id:      u32,
+    pending: VecDeque<Value>,
+}
+
+pub async fn run(socket: &Path, device: Option<Address>, json: bool, action: Action) -> Result<(), Error> {
+    let mut client = Client::connect(socket, device).await?;
+
+    // Nothing but what's asked for, state of the daemon comes along with the answer.
+    // Everything is heard about every device until then, so it's dropped without naming one.
+    client.request(json!({ "command": "unsubscribe", "device": null })).await?;
+    client.pending.clear();
+
+    let print = |message: &Value| match json {
+        true  => println!("{message}"),
+        false => println!("{}", describe(message)),
+    };
+
+    match action {
+        Action::Battery => {
+            let messages = client.request(json!({ "command": "battery" })).await?;
+
+            // Level alone is for old clients
+            let info = messages.iter().rev().find(|m| m["message"] == "battery_info").or(messages.first());
+            info.into_iter().for_each(print);
+        }
+        Action::Steps { set } => {
+            let messages = match set {
+                Some(n) => client.request(json!({ "command": "steps", "set": n })).await?,
+                None    => client.request(json!({ "command": "steps" })).await?,
+            };
+
+            messages.iter().for_each(print);
+        }
+        Action::Heartrate { measure } => {
+            client.request(json!({ "command": "subscribe", "message": "heartrate" })).await?;
+
+            let message = match measure {
+                true => {
+                    client.pending.clear();
+                    client.request(json!({ "command": "heartrate" })).await?;
+
+                    let event = tokio::time::timeout(TIMEOUT, client.event()).await.map_err(|_| Error::Timeout)?;
+                    Some(event?)
+                }
+                false => client.pending.iter().rev().find(|m| m["message"] == "heartrate").cloned(),
+            };
+
+            match message {
+                Some(message) => print(&message),
+                None          => println!("Heart rate wasn't measured yet"),
+            }
+        }
+        Action::Alert { level } => {
+            client.request(json!({ "command": "alert", "level": level })).await?;
+        }
+        Action::Watch { messages } => {
+            let messages = match messages.is_empty() {
+                true  => vec![String::from("all")],
+                false => messages,
+            };
+
+            for message in messages {
+                client.request(json!({ "command": "subscribe", "message": message })).await?;
+            }
+
+            loop {
+                print(&client.event().await?);
+            }
+        }
+        Action::Info => {
+            client.request(json!({ "command": "info" })).await?.iter().for_each(print);
+        }
+    }
+
+    Ok(())
+}
+
+impl Client {
+    async fn connect(socket: &Path, device: Option<Address>) -> Result<Self, Error> {
+        let (reader, writer) = UnixStream::connect(socket).await?.into_split();
+
+        Ok(Self { lines: BufReader::new(reader).lines(), writer, device, id: 0, pending: VecDeque::new() })
+    }
+
+    // Messages answering the request, failures come back as errors
+    async fn request(&mut self, mut request: Value) -> Result<Vec<Value>, Error> {
+        self.id += 1;
+
+        // Requests that name a device on their own, even none, keep it
+        request["id"] = json!(self.id);
+        if let (Some(device), None) = (self.device, request.get("device")) {
+            request["device"] = json!(device);
+        }
+
+        self.writer.write_all(format!("{request}\n").as_bytes()).await?;
+
+        tokio::time::timeout(TIMEOUT, self.answer()).await.map_err(|_| Error::Timeout)?
+    }
+
+    async fn answer(&mut self) -> Result<Vec<Value>, Error> {
+        loop {
+            let mut line = self.line().await?;
+
+            if line.get("message").is_some() {
+                self.pending.push_back(line);
+                continue
+            }
+
+            if line["id"] != self.id {
+                continue
+            }
+
+            if let Some(reason) = line["error"].as_str() {
+                let code = line["code"].as_u64().unwrap_or(0) as u8;
+                return Err(Error::Refused { code, reason: reason.to_owned() })
+            }
+
+            return match line["messages"].take() {
+                Value::Array(messages) => Ok(messages),
+                _                      => Ok(Vec::new()),
+            }
+        }
+    }
+
+    async fn event(&mut self) -> Result<Value, Error> {
+        match self.pending.pop_front() {
+            Some(event) => Ok(event),
+            None        => self.line().await,
+        }
+    }
+
+    async fn line(&mut self) -> Result<Value, Error> {
+        let line = self.lines.next_line().await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+// "AA:BB:CC:DD:EE:FF battery_info level=80 status=not_charging"
+fn describe(message: &Value) -> String {
+    let mut s = String::new();
+
+    if let Some(device) = message["device"].as_str() {
+        s.push_str(device);
+        s.push(' ');
+    }
+
+    s.push_str(message["message"].as_str().unwrap_or("unknown"));
+
+    if let Value::Object(fields) = message {
+        for (key, value) in fields.iter().filter(|(key, _)| *key != "message" && *key != "device") {
+            match value {
+                Value::String(v) => s.push_str(&format!(" {key}={v}")),
+                Value::Null      => {},
+                v                => s.push_str(&format!(" {key}={v}")),
+            }
+        }
+    }
+
+    s
+}
@@ -1,39 +1,104 @@
-use std::io::Read;
+use std::{io::Read, path::PathBuf, time::Duration};
 
+use bluer::Address;
 use byteorder::{ReadBytesExt, LittleEndian};
-use chrono::{Utc, TimeZone};
+use chrono::{NaiveDate, NaiveTime, Utc, TimeZone};
 use log::warn;
+use tokio::sync::oneshot;
 
-use crate::{devices::{capabilities::alert::AlertLevel, WearLocation, DateTime}, error::Error};
+use crate::{ipc::Message, bio::{Bio, Profile, Sex}, devices::{capabilities::{alarm::AlarmSlot, alert::{AlertLevel, Pattern}}, WearLocation, DateTime}, error::Error, store::Metric};
 
 pub const MAGIC: &[u8; 3] = b"CMD";
 
+/// Messages answering a command, each with the device it concerns.
+pub type Reply = Result<Vec<(Address, Message)>, Error>;
+
+// Command aimed at one of the devices, the first connected one when none is given
+#[derive(Debug)]
+pub struct Request {
+    pub device:  Option<Address>,
+    pub command: Command,
+    pub reply:   Option<oneshot::Sender<Reply>>, // legacy clients can't be answered directly
+}
+
 #[derive(Debug)]
 pub enum Command {
-    Alarm(CommandAction),
+    Activity,
+    Aggregate((Range, u32)),
+    Alarm((CommandAction, Option<AlarmSlot>)),
+    AlarmEnable((u8, bool)),
     Alert(AlertLevel),
+    AlertPattern(Pattern),
     Battery,
-    DateTime(DateTime),
+    DateTime((CommandAction, Option<DateTime>)),
+    Devices,
+    Firmware((PathBuf, bool)), // dry run
     Heartrate,
     HeartrateContinuous(bool),
     HeartrateSleep(bool),
+    Info,
     Name,
+    Profile((CommandAction, Option<Profile>)),
+    Query(Range),
+    StepGoal((CommandAction, Option<u32>)),
     Steps((CommandAction, Option<u32>)),
+    Subscribe((Option<u8>, Duration)), // message type, interval
+    Unsubscribe(Option<u8>),            // message type
     WearLocation((CommandAction, Option<WearLocation>)),
 }
 
+#[derive(Debug)]
+pub struct Range {
+    pub metric: Metric,
+    pub device: Option<Address>,
+    pub from:   i64,
+    pub to:     i64,
+}
+
 #[derive(Debug)]
 pub enum CommandAction {
     Get,
     Set,
 }
 
-impl Command {
+impl Request {
+    // kind:   u8,
+    // device: [u8; 6], zeroed for the first device
+    // action: u8,
+    // data
+
     pub fn read(r: &mut impl Read) -> Result<Self, Error> {
         let kind   = r.read_u8()?;
+        let device = address_from_bytes(r)?;
+
+        Command::read(kind, r).map(|command| Request { device, command, reply: None })
+    }
+
+    // Clients that never greeted don't name a device, they talk to the first one
+    pub fn read_untagged(r: &mut impl Read) -> Result<Self, Error> {
+        let kind = r.read_u8()?;
+
+        Command::read(kind, r).map(|command| Request { device: None, command, reply: None })
+    }
+}
+
+impl Command {
+    fn read(kind: u8, r: &mut impl Read) -> Result<Self, Error> {
         let action = r.read_u8().map(CommandAction::try_from)??;
 
         match kind {
+            60  => {
+                let dt = match action {
+                    CommandAction::Get => None,
+                    CommandAction::Set => {
+                        let mut b = [0; 6];
+                        r.read_exact(&mut b)?;
+                        Some(datetime_from_bytes(&b)?)
+                    }
+                };
+
+                Ok(Command::DateTime((action, dt)))
+            }
             80  => {
                 let steps = match action {
                     CommandAction::Get => None,
@@ -42,6 +107,14 @@ impl Command {
 
                 Ok(Command::Steps((action, steps)))
             }
+            81  => {
+                let goal = match action {
+                    CommandAction::Get => None,
+                    CommandAction::Set => Some(r.read_u32::<LittleEndian>()?),
+                };
+
+                Ok(Command::StepGoal((action, goal)))
+            }
             83  => Ok(Command::Battery),
             139 => Ok(Command::Heartrate),
             173 => {
@@ -54,15 +127,92 @@ impl Command {
                     .map(AlertLevel::try_from)?
                     .map(Command::Alert)
             },
+            146 => pattern_from_bytes(r).map(Command::AlertPattern),
+            40  => {
+                let alarm = match action {
+                    CommandAction::Get => None,
+                    CommandAction::Set => Some(alarm_from_bytes(r)?),
+                };
+
+                Ok(Command::Alarm((action, alarm)))
+            }
+            41  => {
+                let slot    = r.read_u8()?;
+                let enabled = r.read_u8()? != 0;
+
+                Ok(Command::AlarmEnable((slot, enabled)))
+            }
+            90  => {
+                let profile = match action {
+                    CommandAction::Get => None,
+                    CommandAction::Set => Some(profile_from_bytes(r)?),
+                };
+
+                Ok(Command::Profile((action, profile)))
+            }
+            110 => {
+                let location = match action {
+                    CommandAction::Get => None,
+                    CommandAction::Set => Some(r.read_u8().map(wearlocation_from_bytes)??),
+                };
+
+                Ok(Command::WearLocation((action, location)))
+            }
+            120 => Ok(Command::Activity),
+            200 => Range::read(r).map(Command::Query),
+            201 => {
+                let range  = Range::read(r)?;
+                let bucket = r.read_u32::<LittleEndian>()?;
+
+                Ok(Command::Aggregate((range, bucket)))
+            },
+            10  => Ok(Command::Devices),
+            11  => {
+                let kind     = r.read_u8()?;
+                let interval = r.read_u32::<LittleEndian>()?;
+
+                // Zeroed type stands for all of them
+                Ok(Command::Subscribe(((kind != 0).then_some(kind), Duration::from_millis(interval as u64))))
+            }
+            12  => {
+                let kind = r.read_u8()?;
+
+                Ok(Command::Unsubscribe((kind != 0).then_some(kind)))
+            }
             244 => Ok(Command::Name),
+            245 => Ok(Command::Info),
+            250 => {
+                let dry = r.read_u8()? != 0;
+
+                let mut path = vec![0; r.read_u8()? as usize];
+                r.read_exact(&mut path)?;
+
+                Ok(Command::Firmware((PathBuf::from(String::from_utf8_lossy(&path).into_owned()), dry)))
+            }
             _   => {
                 warn!("Tried to parse an unknown command kind - {kind}");
-                Err(Error::Nothing)
+                Err(Error::Parse { expected: "known command kind", position: 0, actual: kind })
             }
         }
     }
 }
 
+impl Range {
+    // metric: u8,
+    // from:   i64,
+    // to:     i64,
+    // device: [u8; 6], zeroed for any device
+
+    fn read(r: &mut impl Read) -> Result<Self, Error> {
+        let metric = r.read_u8().map(Metric::try_from)??;
+        let from   = r.read_i64::<LittleEndian>()?;
+        let to     = r.read_i64::<LittleEndian>()?;
+        let device = address_from_bytes(r)?;
+
+        Ok(Self { metric, device, from, to })
+    }
+}
+
 impl TryFrom<u8> for CommandAction {
     type Error = Error;
 
@@ -75,6 +225,80 @@ impl TryFrom<u8> for CommandAction {
     }
 }
 
+// slot:    u8,
+// enabled: u8,
+// hour:    u8,
+// minute:  u8,
+// smart:   u8,
+// repeat:  u8,
+fn alarm_from_bytes(r: &mut impl Read) -> Result<AlarmSlot, Error> {
+    let mut b = [0; 6];
+    r.read_exact(&mut b)?;
+
+    let time = NaiveTime::from_hms_opt(b[2] as u32, b[3] as u32, 0)
+        .ok_or(Error::Parse { expected: "0..23", position: 2, actual: b[2] })?;
+
+    Ok(AlarmSlot { slot: b[0], enabled: b[1] != 0, time, smart: b[4] != 0, repeat: b[5] })
+}
+
+// repeat: u8,
+// on:     u16, ms
+// off:    u16, ms
+// color:  u8,  whether colour follows
+// rgb:    [u8; 3]
+fn pattern_from_bytes(r: &mut impl Read) -> Result<Pattern, Error> {
+    let repeat = r.read_u8()?;
+    let on     = r.read_u16::<LittleEndian>()?;
+    let off    = r.read_u16::<LittleEndian>()?;
+
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+
+    let color = (b[0] != 0).then_some([b[1], b[2], b[3]]);
+
+    Ok(Pattern { repeat, on, off, color }.clamped())
+}
+
+// sex:    u8,
+// birth:  u16, u8, u8, year, month, day
+// height: u8,
+// weight: u8,
+// alias:  u8, followed by as many bytes of UTF-8
+fn profile_from_bytes(r: &mut impl Read) -> Result<Profile, Error> {
+    let sex  = r.read_u8().map(Sex::try_from)??;
+    let year = r.read_u16::<LittleEndian>()?;
+
+    let mut b = [0; 5];
+    r.read_exact(&mut b)?;
+
+    let birth_date = NaiveDate::from_ymd_opt(year as i32, b[0] as u32, b[1] as u32)
+        .ok_or(Error::Parse { expected: "valid date", position: 3, actual: b[0] })?;
+
+    let mut alias = vec![0; b[4] as usize];
+    r.read_exact(&mut alias)?;
+
+    Ok(Profile {
+        alias: String::from_utf8_lossy(&alias).into_owned(),
+        bio:   Bio { sex, birth_date, height: b[2], weight: b[3] },
+    })
+}
+
+fn address_from_bytes(r: &mut impl Read) -> Result<Option<Address>, Error> {
+    let mut b = [0; 6];
+    r.read_exact(&mut b)?;
+
+    Ok((b != [0; 6]).then_some(Address::new(b)))
+}
+
+pub fn wearlocation_as_byte(location: WearLocation) -> u8 {
+    match location {
+        WearLocation::Left   => 0,
+        WearLocation::Right  => 1,
+        WearLocation::Neck   => 2,
+        WearLocation::Pocket => 3,
+    }
+}
+
 fn wearlocation_from_bytes(value: u8) -> Result<WearLocation, Error> {
     match value {
         0 => Ok(WearLocation::Left),
@@ -97,5 +321,8 @@ fn datetime_from_bytes(b: &[u8]) -> Result<DateTime, Error> {
     let min   = b[4] as u32;
     let sec   = b[5] as u32;
 
-    Ok(Utc.with_ymd_and_hms(year, month, day, hour, min, sec).unwrap().into())
+    Utc.with_ymd_and_hms(year, month, day, hour, min, sec)
+        .single()
+        .map(Into::into)
+        .ok_or(Error::Parse { expected: "valid date", position: 0, actual: b[0] })
 }
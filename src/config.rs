@@ -0,0 +1,86 @@
+use std::{path::{Path, PathBuf}, fs, env};
+
+use bluer::Address;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{Error, bio::Bio, devices::{WearLocation, capabilities::{alarm::AlarmSlot, clock::ClockMode}}, notifications::Notifications, state, store};
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub socket:     PathBuf,
+    pub state:      PathBuf,
+    pub store:      PathBuf,
+    pub addresses:  Vec<Address>, // empty for every supported device
+    pub adapter:    Option<String>,
+    pub min_rssi:   Option<i16>,
+    pub log_level:  LevelFilter,
+    pub alias:      Option<String>, // name the daemon pairs with, $USER if not set
+    pub bio:        Bio,
+    pub alarms:     Vec<AlarmSlot>,
+    pub wear:       Option<WearLocation>,
+    pub step_goal:  Option<u32>,
+    pub clock:      ClockMode,
+    pub clock_sync: u64, // seconds, 0 disables
+    pub simulate:   bool,
+
+    pub notifications: Option<Notifications>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket:     default_socket(),
+            state:      state::default_path(),
+            store:      store::default_path(),
+            addresses:  Vec::new(),
+            adapter:    None,
+            min_rssi:   None,
+            log_level:  if cfg!(debug_assertions) { LevelFilter::Debug } else { LevelFilter::Warn },
+            alias:      None,
+            bio:        Bio::default(),
+            alarms:     Vec::new(),
+            wear:       None,
+            step_goal:  None,
+            clock:      ClockMode::default(),
+            clock_sync: 3600,
+            simulate:   false,
+
+            notifications: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads configuration from `path`, or from the default location if none is given.
+    /// Missing default configuration is not an error, defaults are used instead.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None       => (default_path(), false),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(Into::into),
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn default_path() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_default()
+        .join("inoli")
+        .join("config.toml")
+}
+
+fn default_socket() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("inoli.sock")
+}
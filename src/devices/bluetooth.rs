@@ -1,15 +1,22 @@
 use std::ops::Deref;
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use bluer::gatt::WriteOp;
-use bluer::gatt::remote::{Characteristic, CharacteristicWriteRequest};
 
 use crate::Error;
 
+use super::Info;
+use super::transport::{Characteristic, Transport, WriteType};
+use super::capabilities::activity::Activity;
+use super::capabilities::alarm::Alarm;
 use super::capabilities::alert::Alert;
+use super::capabilities::clock::Clock;
 use super::capabilities::battery::Battery;
+use super::capabilities::firmware::Firmware;
 use super::capabilities::heartrate::HeartRate;
+use super::capabilities::profile::UserProfile;
 use super::capabilities::steps::Steps;
+use super::capabilities::wear::Wear;
 
 pub mod uuid {
     use uuid::{uuid, Uuid};
@@ -24,22 +31,37 @@ pub mod uuid {
     pub const SERVICE_CHANGED:          Uuid = uuid!("00002A05-0000-1000-8000-00805f9b34fb");
 }
 
-pub const WITH_RESPONSE: &CharacteristicWriteRequest = &CharacteristicWriteRequest {
-    offset: 0,
-    op_type: WriteOp::Request,
-    prepare_authorize: false,
-    _non_exhaustive: (),
-};
+pub const WITH_RESPONSE:    WriteType = WriteType::Request;
+pub const WITHOUT_RESPONSE: WriteType = WriteType::Command;
 
 #[async_trait]
-pub trait BluetoothDevice where Self: Sync + Send + Deref<Target = bluer::Device> {
+pub trait BluetoothDevice where Self: Sync + Send + Deref<Target = dyn Transport> {
     async fn connect(&mut self) -> Result<(), Error>;
 
-    fn characteristic(&self, uuid: ::uuid::Uuid) -> &Characteristic;
+    // Devices that don't pair are usable as soon as they're connected
+    async fn authenticate(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn info(&self) -> Result<Info, Error> {
+        Ok(Info { name: self.name().await?.unwrap_or_default(), ..Info::default() })
+    }
+
+    fn try_characteristic(&self, uuid: ::uuid::Uuid) -> Option<&Arc<dyn Characteristic>>;
+
+    fn characteristic(&self, uuid: ::uuid::Uuid) -> &Arc<dyn Characteristic> {
+        self.try_characteristic(uuid).unwrap_or_else(|| panic!("device doesn't have characteristic {uuid}"))
+    }
     // fn command(&self, command: Command) -> Result<(), Error>;
 
     fn alert(&self)     -> Option<&(dyn Alert + Sync + Send)>;
     fn heartrate(&self) -> Option<&(dyn HeartRate + Sync + Send)>;
     fn steps(&self)     -> Option<&(dyn Steps + Sync + Send)>;
     fn battery(&self)   -> Option<&(dyn Battery + Sync + Send)>;
+    fn activity(&self)  -> Option<&(dyn Activity + Sync + Send)>;
+    fn alarm(&self)     -> Option<&(dyn Alarm + Sync + Send)>;
+    fn wear(&self)      -> Option<&(dyn Wear + Sync + Send)>;
+    fn clock(&self)     -> Option<&(dyn Clock + Sync + Send)>;
+    fn profile(&self)   -> Option<&(dyn UserProfile + Sync + Send)>;
+    fn firmware(&self)  -> Option<&(dyn Firmware + Sync + Send)>;
 }
@@ -0,0 +1,19 @@
+use crate::{Error, devices::DateTime};
+
+use async_trait::async_trait;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ActivitySample {
+    pub timestamp: DateTime,
+    pub category:  u8,
+    pub intensity: u8,
+    pub steps:     u8,
+    pub heartrate: Option<u8>,
+}
+
+#[async_trait]
+pub trait Activity {
+    /// Fetches activity recorded by the device since the last sync, device frees acknowledged data.
+    /// Samples are handed to `acknowledged` block by block, so a failed sync loses none of them.
+    async fn sync_activity(&self, acknowledged: &mut (dyn FnMut(Vec<ActivitySample>) + Send)) -> Result<(), Error>;
+}
@@ -1,3 +1,11 @@
+use crate::Error;
+
+use async_trait::async_trait;
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AlarmFrequency {
     Once,
     Monday,
@@ -30,3 +38,58 @@ impl AlarmFrequency {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlarmSlot {
+    pub slot:    u8,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(deserialize_with = "time_from_str", serialize_with = "time_to_str")]
+    pub time:    NaiveTime,
+    #[serde(default)]
+    pub smart:   bool,
+    #[serde(default, deserialize_with = "repeat_from_days")]
+    pub repeat:  u8, // AlarmFrequency bits
+}
+
+#[async_trait]
+pub trait Alarm {
+    fn alarm_slots(&self) -> u8;
+    async fn set_alarm(&self, alarm: &AlarmSlot) -> Result<(), Error>;
+}
+
+fn enabled() -> bool { true }
+
+fn time_from_str<'de, D>(d: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let s = String::deserialize(d)?;
+
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(de::Error::custom)
+}
+
+fn time_to_str<S>(time: &NaiveTime, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    s.collect_str(&time.format("%H:%M"))
+}
+
+fn repeat_from_days<'de, D>(d: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repeat {
+        Bits(u8),
+        Days(Vec<AlarmFrequency>),
+    }
+
+    match Repeat::deserialize(d)? {
+        Repeat::Bits(bits) => Ok(bits),
+        Repeat::Days(days) => Ok(days.into_iter().fold(0, |bits, f| bits | f.as_bits())),
+    }
+}
@@ -1,5 +1,8 @@
+use std::time::Duration;
+
 use crate::{Error, devices::bluetooth::BluetoothDevice};
 use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, de};
 
 pub mod uuid {
     use uuid::{uuid, Uuid};
@@ -11,16 +14,64 @@ pub mod uuid {
 #[async_trait]
 pub trait Alert {
     async fn alert(&self, level: AlertLevel) -> Result<(), Error>;
+    async fn pattern(&self, pattern: &Pattern) -> Result<(), Error>;
+    /// Stops vibration, a pattern cut short leaves it running otherwise.
+    async fn stop(&self) -> Result<(), Error>;
 }
 
 pub trait AlertCapable {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AlertLevel {
+    #[default]
     Mild,
     High,
 }
 
+// Longest pattern buzzes for a minute, anything longer is a mistake
+pub const MAX_REPEAT:   u8  = 10;
+pub const MAX_DURATION: u16 = 3000; // ms
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pattern {
+    #[serde(deserialize_with = "repeat_clamped")]
+    pub repeat: u8,
+    #[serde(deserialize_with = "duration_clamped")]
+    pub on:     u16, // ms
+    #[serde(deserialize_with = "duration_clamped")]
+    pub off:    u16, // ms
+    #[serde(deserialize_with = "color_from_str")]
+    pub color:  Option<[u8; 3]>,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            repeat: 1,
+            on:     500,
+            off:    500,
+            color:  None,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn on(&self)  -> Duration { Duration::from_millis(self.on as u64) }
+    pub fn off(&self) -> Duration { Duration::from_millis(self.off as u64) }
+
+    /// Same pattern, cut down to the longest one allowed.
+    pub fn clamped(self) -> Self {
+        Self {
+            repeat: self.repeat.min(MAX_REPEAT),
+            on:     self.on.min(MAX_DURATION),
+            off:    self.off.min(MAX_DURATION),
+            ..self
+        }
+    }
+}
+
 impl TryFrom<u8> for AlertLevel {
     type Error = Error;
 
@@ -44,7 +95,72 @@ impl<T: BluetoothDevice> Alert for T where Self: Sync + Send + AlertCapable {
         T::characteristic(self, uuid::ALERT_LEVEL)
             .write(&payload)
             .await
-            .map_err(Into::into)
     }
+
+    // Immediate Alert has no durations, vibration is switched on and off instead, colour is ignored
+    async fn pattern(&self, pattern: &Pattern) -> Result<(), Error> {
+        let characteristic = T::characteristic(self, uuid::ALERT_LEVEL);
+
+        for _ in 0..pattern.repeat {
+            characteristic.write(&[2]).await?;
+            tokio::time::sleep(pattern.on()).await;
+            characteristic.write(&[0]).await?;
+            tokio::time::sleep(pattern.off()).await;
+        }
+
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), Error> {
+        T::characteristic(self, uuid::ALERT_LEVEL).write(&[0]).await
+    }
+}
+
+fn repeat_clamped<'de, D>(d: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>
+{
+    u64::deserialize(d).map(|n| n.min(MAX_REPEAT as u64) as u8)
+}
+
+fn duration_clamped<'de, D>(d: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>
+{
+    u64::deserialize(d).map(|ms| ms.min(MAX_DURATION as u64) as u16)
+}
+
+// "#rrggbb"
+fn color_from_str<'de, D>(d: D) -> Result<Option<[u8; 3]>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let s = String::deserialize(d)?;
+
+    let hex = s.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.is_ascii())
+        .ok_or_else(|| de::Error::custom("expected colour as #rrggbb"))?;
+
+    let mut color = [0; 3];
+    for (i, c) in color.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(de::Error::custom)?;
+    }
+
+    Ok(Some(color))
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_is_clamped() {
+        let pattern: Pattern = serde_json::from_str(r##"{"repeat": 255, "on": 65535, "off": 100000, "color": "#00ff00"}"##).unwrap();
+
+        assert_eq!((pattern.repeat, pattern.on, pattern.off), (MAX_REPEAT, MAX_DURATION, MAX_DURATION));
+        assert_eq!(pattern.color, Some([0, 255, 0]));
+
+        let pattern = Pattern { repeat: 3, on: 200, off: 100, color: None }.clamped();
+        assert_eq!((pattern.repeat, pattern.on, pattern.off), (3, 200, 100));
+    }
+}
@@ -1,11 +1,27 @@
 use std::pin::Pin;
+use std::time::Duration;
 
-use crate::Error;
+use crate::{Error, devices::{DateTime, bluetooth::BluetoothDevice}};
 
 use async_trait::async_trait;
-use futures::Stream;
+use futures::{Stream, StreamExt, future, stream};
+use serde::Serialize;
 
-#[derive(Debug)]
+pub mod uuid {
+    use uuid::{uuid, Uuid};
+
+    pub const BATTERY_LEVEL:       Uuid = uuid!("00002A19-0000-1000-8000-00805f9b34fb");
+    pub const BATTERY_POWER_STATE: Uuid = uuid!("00002A1A-0000-1000-8000-00805f9b34fb");
+}
+
+// Devices that can't notify about their battery level are polled instead
+const POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+// Devices that don't report their status are low below this level
+const LOW_LEVEL: u8 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BatteryStatus {
     Low,
     Charging,
@@ -13,10 +29,21 @@ pub enum BatteryStatus {
     Full,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct BatteryInfo {
-    pub level:  u8,
-    pub status: Option<BatteryStatus>,
+    pub level:        u8,
+    pub status:       Option<BatteryStatus>,
+    pub last_charged: Option<DateTime>,
+    pub cycles:       Option<u16>,
+}
+
+/// Changes of the battery worth telling about on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryEvent {
+    Low,
+    Charging,
+    Charged,
 }
 
 #[async_trait]
@@ -24,3 +51,130 @@ pub trait Battery {
     async fn battery_stream(&self) -> Result<Pin<Box<dyn Stream<Item = BatteryInfo> + Send>>, Error>;
     async fn battery(&self) -> Result<BatteryInfo, Error>;
 }
+
+pub trait BatteryCapable {}
+
+impl BatteryStatus {
+    // present:     u2,
+    // discharging: u2,
+    // charging:    u2,
+    // level:       u2,
+
+    fn from_power_state(b: u8) -> Option<Self> {
+        match ((b >> 4) & 0b11, (b >> 6) & 0b11) {
+            (3, _) => Some(BatteryStatus::Charging),
+            (_, 3) => Some(BatteryStatus::Low),
+            (2, _) => Some(BatteryStatus::NotCharging),
+            _      => None,
+        }
+    }
+}
+
+impl BatteryInfo {
+    fn is_low(&self) -> bool {
+        match self.status {
+            Some(status) => status == BatteryStatus::Low,
+            None         => self.level <= LOW_LEVEL,
+        }
+    }
+}
+
+impl BatteryEvent {
+    /// Event caused by going from `previous` to `current`, anything is new without `previous`.
+    pub fn between(previous: Option<&BatteryInfo>, current: &BatteryInfo) -> Option<Self> {
+        let status = previous.and_then(|p| p.status);
+
+        match current.status {
+            Some(BatteryStatus::Full)     if status != Some(BatteryStatus::Full)     => Some(BatteryEvent::Charged),
+            Some(BatteryStatus::Charging) if status != Some(BatteryStatus::Charging) => Some(BatteryEvent::Charging),
+            _ if current.is_low() && !previous.is_some_and(BatteryInfo::is_low)     => Some(BatteryEvent::Low),
+            _ => None,
+        }
+    }
+}
+
+enum Update {
+    Level(u8),
+    Status(Option<BatteryStatus>),
+}
+
+#[async_trait]
+impl<T: BluetoothDevice> Battery for T where Self: Sync + Send + BatteryCapable {
+    async fn battery_stream(&self) -> Result<Pin<Box<dyn Stream<Item = BatteryInfo> + Send>>, Error> {
+        let level = T::characteristic(self, uuid::BATTERY_LEVEL);
+
+        let levels = match level.can_notify().await? {
+            true  => level.notify().await?.boxed(),
+            false => {
+                let level = level.clone();
+
+                stream::unfold(tokio::time::interval(POLL_INTERVAL), move |mut interval| {
+                    let level = level.clone();
+
+                    async move {
+                        interval.tick().await;
+                        Some((level.read().await.unwrap_or_default(), interval))
+                    }
+                }).boxed()
+            }
+        };
+
+        let levels = levels.filter_map(|payload| async move { payload.first().copied().map(Update::Level) });
+
+        let states = match T::try_characteristic(self, uuid::BATTERY_POWER_STATE) {
+            Some(state) if state.can_notify().await? => {
+                state.notify().await?
+                    .filter_map(|payload| async move { payload.first().map(|b| Update::Status(BatteryStatus::from_power_state(*b))) })
+                    .boxed()
+            }
+            _ => stream::empty().boxed(),
+        };
+
+        let current = Battery::battery(self).await?;
+
+        let stream = stream::select(levels, states)
+            .scan(current, |info, update| {
+                match update {
+                    Update::Level(level)   => info.level  = level,
+                    Update::Status(status) => info.status = status,
+                }
+
+                future::ready(Some(*info))
+            });
+
+        Ok(Box::pin(stream))
+    }
+
+    async fn battery(&self) -> Result<BatteryInfo, Error> {
+        let payload = T::characteristic(self, uuid::BATTERY_LEVEL).read().await?;
+
+        let level = *payload.first().ok_or(Error::Length { expected: 1, actual: 0 })?;
+
+        let status = match T::try_characteristic(self, uuid::BATTERY_POWER_STATE) {
+            Some(state) => state.read().await?.first().and_then(|b| BatteryStatus::from_power_state(*b)),
+            None        => None,
+        };
+
+        // Neither is part of the standard service
+        Ok(BatteryInfo { level, status, last_charged: None, cycles: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_state() {
+        // Present, discharging, charging, good level
+        assert_eq!(BatteryStatus::from_power_state(0b10_11_10_11), Some(BatteryStatus::Charging));
+        // Present, discharging, not charging, good level
+        assert_eq!(BatteryStatus::from_power_state(0b10_10_11_11), Some(BatteryStatus::NotCharging));
+        // Present, discharging, not charging, critically low
+        assert_eq!(BatteryStatus::from_power_state(0b11_10_11_11), Some(BatteryStatus::Low));
+        // Charging tells more than a low level
+        assert_eq!(BatteryStatus::from_power_state(0b11_11_10_11), Some(BatteryStatus::Charging));
+        // Nothing is known
+        assert_eq!(BatteryStatus::from_power_state(0), None);
+    }
+}
@@ -0,0 +1,19 @@
+use crate::{Error, devices::DateTime};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// Devices keep wall clock without a timezone, this decides which one they show.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockMode {
+    Local,
+    #[default]
+    Utc,
+}
+
+#[async_trait]
+pub trait Clock {
+    async fn datetime(&self) -> Result<DateTime, Error>;
+    async fn set_datetime(&self, dt: &DateTime) -> Result<(), Error>;
+}
@@ -0,0 +1,47 @@
+use crate::{Error, devices::Version};
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Device,
+    Heartrate,
+}
+
+// Image of a single chip inside of a firmware file
+#[derive(Debug, Clone, Copy)]
+pub struct Part {
+    pub target:   Target,
+    pub version:  Version,
+    pub offset:   usize,
+    pub length:   usize,
+    pub crc:      u16,
+    pub outdated: bool, // differs from the version on device
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub target: Target,
+    pub sent:   usize,
+    pub total:  usize,
+}
+
+#[async_trait]
+pub trait Firmware {
+    /// Checks that firmware is meant for this device and describes it, nothing is written.
+    fn inspect(&self, firmware: &[u8]) -> Result<Vec<Part>, Error>;
+
+    /// Writes outdated parts of the firmware and reboots the device.
+    async fn flash(&self, firmware: &[u8], progress: &mut (dyn FnMut(Progress) + Send)) -> Result<(), Error>;
+}
+
+impl From<Target> for u8 {
+    fn from(target: Target) -> Self {
+        match target {
+            Target::Device    => 0,
+            Target::Heartrate => 1,
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use std::pin::Pin;
 
-use crate::{Error, devices::bluetooth::{WITH_RESPONSE, BluetoothDevice}};
+use crate::{Error, devices::{bluetooth::{WITH_RESPONSE, BluetoothDevice}, transport::Characteristic}};
 
 use async_trait::async_trait;
 use futures::{Stream, StreamExt};
+use log::{debug, warn};
 
 pub mod uuid {
     use uuid::{uuid, Uuid};
@@ -18,9 +19,61 @@ const MANUAL:     [u8; 3] = [0x15, 0x2, 0x1];
 const CONTINUOUS: [u8; 3] = [0x15, 0x1, 0x0];
 const SLEEP:      [u8; 3] = [0x15, 0x0, 0x0];
 
+#[derive(Debug)]
+pub struct HeartRateMeasurement {
+    pub bpm:     u16,
+    pub contact: Option<bool>,
+    pub energy:  Option<u16>, // kJ
+    pub rr:      Vec<u16>,    // 1/1024 s
+}
+
+impl TryFrom<&[u8]> for HeartRateMeasurement {
+    type Error = Error;
+
+    // flags:   u8,
+    // bpm:     u8 | u16,
+    // energy:  u16,
+    // rr:      [u16],
+
+    fn try_from(b: &[u8]) -> Result<Self, Self::Error> {
+        const WIDE:    u8 = 0b1;
+        const CONTACT: u8 = 0b10;
+        const SENSOR:  u8 = 0b100;
+        const ENERGY:  u8 = 0b1000;
+        const RR:      u8 = 0b10000;
+
+        let u16_at = |i: usize| {
+            b.get(i..i + 2)
+                .map(|v| u16::from_le_bytes([v[0], v[1]]))
+                .ok_or(Error::Length { expected: i + 2, actual: b.len() })
+        };
+
+        let flags = *b.first().ok_or(Error::Length { expected: 2, actual: 0 })?;
+
+        let (bpm, mut i) = match flags & WIDE != 0 {
+            true  => (u16_at(1)?, 3),
+            false => (*b.get(1).ok_or(Error::Length { expected: 2, actual: b.len() })? as u16, 2),
+        };
+
+        let contact = (flags & SENSOR != 0).then_some(flags & CONTACT != 0);
+
+        let energy = match flags & ENERGY != 0 {
+            true  => { i += 2; Some(u16_at(i - 2)?) }
+            false => None,
+        };
+
+        let rr = match flags & RR != 0 {
+            true  => b[i..].chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]])).collect(),
+            false => Vec::new(),
+        };
+
+        Ok(Self { bpm, contact, energy, rr })
+    }
+}
+
 #[async_trait]
 pub trait HeartRate {
-    async fn nofity_heartrate(&self) -> Result<Pin<Box<dyn Stream<Item = u8> + Send>>, Error>;
+    async fn nofity_heartrate(&self) -> Result<Pin<Box<dyn Stream<Item = HeartRateMeasurement> + Send>>, Error>;
     async fn heartrate_sleep(&self, enable: bool) -> Result<(), Error>;
     async fn heartrate_continuous(&self, enable: bool) -> Result<(), Error>;
     async fn heartrate(&self) -> Result<(), Error>;
@@ -28,15 +81,23 @@ pub trait HeartRate {
 
 pub trait HeartRateCapable {}
 
-#[async_trait] // TODO: Lookup bluetooth heartrate sensor protocol
+pub async fn notify_measurement(characteristic: &dyn Characteristic) -> Result<Pin<Box<dyn Stream<Item = HeartRateMeasurement> + Send>>, Error> {
+    characteristic
+        .notify()
+        .await
+        .map(|stream| stream.filter_map(|payload| async move {
+                 HeartRateMeasurement::try_from(payload.as_slice())
+                     .map_err(|e| warn!("Couldn't parse heart rate measurement: {e}"))
+                     .inspect(|m| debug!("Heart rate: {} BPM, energy: {:?} kJ, RR: {:?}", m.bpm, m.energy, m.rr))
+                     .ok()
+             }))
+        .map(|stream| Box::pin(stream) as _)
+}
+
+#[async_trait]
 impl<T: BluetoothDevice> HeartRate for T where Self: Sync + Send + HeartRateCapable {
-    async fn nofity_heartrate(&self) -> Result<Pin<Box<dyn Stream<Item = u8> + Send>>, Error> {
-        T::characteristic(self, uuid::HEART_RATE_MEASUREMENT)
-            .notify()
-            .await
-            .map_err(Into::into)
-            .map(|stream| stream.map(|v| v[1]))
-            .map(|stream| Box::pin(stream) as _)
+    async fn nofity_heartrate(&self) -> Result<Pin<Box<dyn Stream<Item = HeartRateMeasurement> + Send>>, Error> {
+        notify_measurement(T::characteristic(self, uuid::HEART_RATE_MEASUREMENT).as_ref()).await
     }
 
     async fn heartrate_sleep(&self, enable: bool) -> Result<(), Error> {
@@ -52,7 +113,6 @@ impl<T: BluetoothDevice> HeartRate for T where Self: Sync + Send + HeartRateCapa
         characteristic // Unknown
             .write_ext(&[0x14, 0x0], WITH_RESPONSE)
             .await
-            .map_err(Into::into)
     }
 
     async fn heartrate_continuous(&self, enable: bool) -> Result<(), Error> {
@@ -62,13 +122,39 @@ impl<T: BluetoothDevice> HeartRate for T where Self: Sync + Send + HeartRateCapa
         T::characteristic(self, uuid::HEART_RATE_CONTROL_POINT)
             .write_ext(&payload, WITH_RESPONSE)
             .await
-            .map_err(Into::into)
     }
 
     async fn heartrate(&self) -> Result<(), Error> {
         T::characteristic(self, uuid::HEART_RATE_CONTROL_POINT)
             .write_ext(&MANUAL, WITH_RESPONSE)
             .await
-            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurement() {
+        let m = HeartRateMeasurement::try_from([0, 72].as_slice()).unwrap();
+        assert_eq!((m.bpm, m.contact, m.energy), (72, None, None));
+        assert!(m.rr.is_empty());
+
+        // Wide, in contact, energy and two intervals
+        let m = HeartRateMeasurement::try_from([0b11111, 0x2C, 0x01, 10, 0, 0, 4, 0, 2].as_slice()).unwrap();
+        assert_eq!((m.bpm, m.contact, m.energy), (300, Some(true), Some(10)));
+        assert_eq!(m.rr, [1024, 512]);
+
+        let m = HeartRateMeasurement::try_from([0b100, 60].as_slice()).unwrap();
+        assert_eq!(m.contact, Some(false));
+    }
+
+    #[test]
+    fn measurement_too_short() {
+        assert!(matches!(HeartRateMeasurement::try_from([].as_slice()), Err(Error::Length { .. })));
+        assert!(matches!(HeartRateMeasurement::try_from([0].as_slice()), Err(Error::Length { .. })));
+        assert!(matches!(HeartRateMeasurement::try_from([0b1, 60].as_slice()), Err(Error::Length { .. })));
+        assert!(matches!(HeartRateMeasurement::try_from([0b1000, 60, 1].as_slice()), Err(Error::Length { .. })));
     }
 }
@@ -0,0 +1,9 @@
+use crate::{Error, bio::User};
+
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait UserProfile {
+    /// Sends user to the device, which might ask to confirm it again.
+    async fn set_user(&self, user: &User) -> Result<(), Error>;
+}
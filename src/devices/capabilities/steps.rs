@@ -10,6 +10,7 @@ pub trait Steps {
     async fn notify_steps(&self) -> Result<Pin<Box<dyn Stream<Item = u32> + Send>>, Error>;
     async fn set_steps(&self, steps: u32) -> Result<(), Error>;
     async fn steps(&self) -> Result<u32, Error>;
+    async fn set_goal(&self, steps: u32) -> Result<(), Error>;
 }
 
 pub trait StepsCapable {}
@@ -0,0 +1,8 @@
+use crate::{Error, devices::WearLocation};
+
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Wear {
+    async fn set_wear_location(&self, location: WearLocation) -> Result<(), Error>;
+}
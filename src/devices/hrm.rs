@@ -0,0 +1,135 @@
+use super::bluetooth::BluetoothDevice;
+use super::capabilities::activity::Activity;
+use super::capabilities::alarm::Alarm;
+use super::capabilities::alert::Alert;
+use super::capabilities::battery::{self, Battery, BatteryCapable};
+use super::capabilities::clock::Clock;
+use super::capabilities::firmware::Firmware;
+use super::capabilities::heartrate::{HeartRate, HeartRateMeasurement, notify_measurement, uuid};
+use super::capabilities::profile::UserProfile;
+use super::capabilities::steps::Steps;
+use super::capabilities::wear::Wear;
+use super::transport::{Characteristic, Transport};
+use crate::bio::User;
+use crate::Error;
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use derive_more::Deref;
+use futures::Stream;
+
+// Any device that exposes standard Heart Rate Service (0x180D), e.g. chest straps.
+#[derive(Deref)]
+pub struct HeartRateMonitor {
+    #[deref(forward)]
+    device: Box<dyn Transport>,
+
+    pub characteristics: HashMap<::uuid::Uuid, Arc<dyn Characteristic>>,
+}
+
+impl HeartRateMonitor {
+    pub fn boxed(device: Box<dyn Transport>, _user: User) -> Box<dyn BluetoothDevice> {
+        Box::from(Self {
+            device,
+            characteristics: HashMap::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl BluetoothDevice for HeartRateMonitor {
+    async fn connect(&mut self) -> Result<(), Error> {
+        if !self.is_connected().await? {
+            self.device.connect().await?;
+        }
+
+        self.characteristics = self.device.characteristics().await?;
+
+        // Service is advertised before its characteristics are resolved, if it's there at all
+        if !self.characteristics.contains_key(&uuid::HEART_RATE_MEASUREMENT) {
+            return Err(Error::Unsupported(format!("device without characteristic {}", uuid::HEART_RATE_MEASUREMENT)))
+        }
+
+        Ok(())
+    }
+
+    fn try_characteristic(&self, uuid: ::uuid::Uuid) -> Option<&Arc<dyn Characteristic>> {
+        self.characteristics.get(&uuid)
+    }
+
+    fn alert(&self)     -> Option<&(dyn Alert + Sync + Send)>     { None }
+    fn heartrate(&self) -> Option<&(dyn HeartRate + Sync + Send)> { Some(self) }
+    fn steps(&self)     -> Option<&(dyn Steps + Sync + Send)>     { None }
+    fn battery(&self)   -> Option<&(dyn Battery + Sync + Send)>   {
+        self.characteristics.contains_key(&battery::uuid::BATTERY_LEVEL).then_some(self)
+    }
+    fn activity(&self)  -> Option<&(dyn Activity + Sync + Send)>  { None }
+    fn alarm(&self)     -> Option<&(dyn Alarm + Sync + Send)>     { None }
+    fn wear(&self)      -> Option<&(dyn Wear + Sync + Send)>      { None }
+    fn clock(&self)     -> Option<&(dyn Clock + Sync + Send)>     { None }
+    fn profile(&self)   -> Option<&(dyn UserProfile + Sync + Send)> { None }
+    fn firmware(&self)  -> Option<&(dyn Firmware + Sync + Send)>    { None }
+}
+
+impl BatteryCapable for HeartRateMonitor {}
+
+// Sensors stream measurements on their own while notifications are enabled,
+// there is nothing to switch on the device side.
+#[async_trait]
+impl HeartRate for HeartRateMonitor {
+    async fn nofity_heartrate(&self) -> Result<Pin<Box<dyn Stream<Item = HeartRateMeasurement> + Send>>, Error> {
+        notify_measurement(self.characteristic(uuid::HEART_RATE_MEASUREMENT).as_ref()).await
+    }
+
+    async fn heartrate_sleep(&self, _enable: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn heartrate_continuous(&self, _enable: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn heartrate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bio::{Bio, User};
+    use crate::devices::capabilities::clock::ClockMode;
+    use crate::devices::simulator::Simulator;
+
+    use bluer::Address;
+
+    // Connected device whose characteristics haven't been resolved
+    struct Unresolved;
+
+    #[async_trait]
+    impl Transport for Unresolved {
+        fn address(&self) -> Address { Address::any() }
+        async fn name(&self) -> Result<Option<String>, Error> { Ok(None) }
+        async fn connect(&self) -> Result<(), Error> { Ok(()) }
+        async fn is_connected(&self) -> Result<bool, Error> { Ok(true) }
+        async fn characteristics(&self) -> Result<HashMap<::uuid::Uuid, Arc<dyn Characteristic>>, Error> { Ok(HashMap::new()) }
+    }
+
+    fn user() -> User {
+        User { id: 0, alias: String::new(), bio: Bio::default(), clock: ClockMode::Utc }
+    }
+
+    #[tokio::test]
+    async fn needs_measurement() {
+        let mut unresolved = HeartRateMonitor::boxed(Box::new(Unresolved), user());
+        assert!(matches!(unresolved.connect().await, Err(Error::Unsupported(_))));
+
+        // Simulated 1S has standard measurement characteristic too
+        let mut monitor = HeartRateMonitor::boxed(Box::new(Simulator::new(Address::new([1; 6]))), user());
+        monitor.connect().await.unwrap();
+        assert!(monitor.heartrate().unwrap().nofity_heartrate().await.is_ok());
+    }
+}
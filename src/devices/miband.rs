@@ -1,31 +1,37 @@
-use super::bluetooth::{WITH_RESPONSE, BluetoothDevice};
-use super::capabilities::alarm::AlarmFrequency;
-use super::capabilities::alert::{AlertCapable, Alert};
+use super::bluetooth::{WITH_RESPONSE, WITHOUT_RESPONSE, BluetoothDevice};
+use super::capabilities::activity::{Activity, ActivitySample};
+use super::capabilities::alarm::{Alarm, AlarmSlot};
+use super::capabilities::alert::{self, Alert, AlertLevel, Pattern};
+use super::capabilities::clock::{Clock, ClockMode};
 use super::capabilities::battery::{BatteryStatus, BatteryInfo, Battery};
-use super::capabilities::heartrate::{HeartRateCapable, HeartRate};
+use super::capabilities::firmware::{Firmware, Part, Progress, Target};
+use super::capabilities::heartrate::{self, HeartRateCapable, HeartRate};
+use super::capabilities::profile::UserProfile;
 use super::capabilities::steps::Steps;
-use super::{DateTime, Version, WearLocation};
-use crate::bio::{Bio, Sex};
+use super::capabilities::wear::Wear;
+use super::transport::{Characteristic, Transport};
+use super::{DateTime, Info, Version, WearLocation};
+use crate::bio::{Sex, User};
 use crate::{Error, ensure_length};
 
 use std::collections::HashMap;
 use std::convert::{TryInto, TryFrom};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, mem};
 
 use derive_more::Deref;
 use async_trait::async_trait;
-use bluer::gatt::remote::Characteristic;
-use bluer::{Device, Address};
-use chrono::{Datelike, Timelike, TimeZone, Utc, Local};
+use chrono::{Datelike, NaiveTime, Timelike, TimeZone, Utc, Local};
 
-use crc::{Crc, CRC_8_MAXIM_DOW};
+use crc::{Crc, CRC_8_MAXIM_DOW, CRC_16_IBM_3740};
 use futures::{StreamExt, Stream, pin_mut};
 use log::debug;
 
-pub const ADDRESS: Address = Address::new([0xC8, 0x0F, 0x10, 0x80, 0xD0, 0xAA]);
+pub const MANUFACTURER_ID: u16 = 0x0157; // Anhui Huami
 
-mod uuid {
+pub mod uuid {
     use uuid::{uuid, Uuid};
 
     pub const MI_SERVICES:              Uuid = uuid!("0000fee0-0000-1000-8000-00805f9b34fb");
@@ -40,6 +46,7 @@ mod uuid {
     pub const DATE_TIME:                Uuid = uuid!("0000ff0a-0000-1000-8000-00805f9b34fb");
     pub const BATTERY_INFO:             Uuid = uuid!("0000ff0c-0000-1000-8000-00805f9b34fb");
     pub const PAIR:                     Uuid = uuid!("0000ff0f-0000-1000-8000-00805f9b34fb");
+    pub const FIRMWARE:                 Uuid = uuid!("0000ff08-0000-1000-8000-00805f9b34fb");
     pub const MAC:                      Uuid = uuid!("0000fec9-0000-1000-8000-00805f9b34fb");
 
     /* Unknown 
@@ -58,6 +65,11 @@ pub mod notifications {
     pub const ALARM:             &[u8] = &[0x23];
     pub const LE_PARAMS_SUCCESS: &[u8] = &[0x8];
 
+    pub mod firmware {
+        pub const FAILED:       &[u8] = &[0xB];
+        pub const SUCCESS:      &[u8] = &[0xC];
+    }
+
     pub mod auth {
         pub const AWAITING:     &[u8] = &[0x13];
         pub const CONFIRMED:    &[u8] = &[0xA];
@@ -67,42 +79,86 @@ pub mod notifications {
     }
 }
 
-mod control {
+pub mod control {
     pub type Command = u8;
 
-    pub const ALARM:         Command = 0x4;
-    pub const STEP_GOAL:     Command = 0x5;
-    pub const COLLECT_DATA:  Command = 0x6;
-    pub const FACTORY_RESET: Command = 0x9;
-    pub const SYNC:          Command = 0xB;
-    pub const REBOOT:        Command = 0xC;
-    pub const WEAR_LOCATION: Command = 0xF;
-    pub const SET_STEPS:     Command = 0x14;
+    pub const ALARM:            Command = 0x4;
+    pub const STEP_GOAL:        Command = 0x5;
+    pub const COLLECT_DATA:     Command = 0x6;
+    pub const FIRMWARE_INFO:    Command = 0x7;
+    pub const VIBRATE:          Command = 0x8;
+    pub const FACTORY_RESET:    Command = 0x9;
+    pub const CONFIRM_ACTIVITY: Command = 0xA;
+    pub const SYNC:             Command = 0xB;
+    pub const REBOOT:           Command = 0xC;
+    pub const COLOR:            Command = 0xE;
+    pub const WEAR_LOCATION:    Command = 0xF;
+    pub const STOP_SYNC:        Command = 0x11;
+    pub const STOP_VIBRATION:   Command = 0x13;
+    pub const SET_STEPS:        Command = 0x14;
 }
 
-pub trait Model {}
+// Everything the band is talked to through
+const REQUIRED: [::uuid::Uuid; 11] = [
+    uuid::DEVICE_INFO,
+    uuid::NOTIFICATIONS,
+    uuid::USER_INFO,
+    uuid::CONTROL,
+    uuid::STEPS,
+    uuid::ACTIVITY,
+    uuid::FIRMWARE,
+    uuid::LE_PARAMS,
+    uuid::DATE_TIME,
+    uuid::BATTERY_INFO,
+    alert::uuid::ALERT_LEVEL,
+];
+
+// Heart rate sensor, on models that have one
+const REQUIRED_HEART_RATE: [::uuid::Uuid; 2] = [
+    heartrate::uuid::HEART_RATE_MEASUREMENT,
+    heartrate::uuid::HEART_RATE_CONTROL_POINT,
+];
+
+// Band stays silent for a while between activity blocks, but not this long
+const ACTIVITY_TIMEOUT:       Duration = Duration::from_secs(10);
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(30);
+const FIRMWARE_TIMEOUT:       Duration = Duration::from_secs(60);
+
+pub trait Model: Sync + Send + 'static {
+    const HEART_RATE: bool;
+    const ACTIVITY_SAMPLE: usize; // bytes per minute of activity
+}
+
+// 1 and 1A
+pub enum One {}
+impl Model for One {
+    const HEART_RATE: bool = false;
+    const ACTIVITY_SAMPLE: usize = 3;
+}
 
 pub enum OneS {}
-impl Model for OneS {}
+impl Model for OneS {
+    const HEART_RATE: bool = true;
+    const ACTIVITY_SAMPLE: usize = 4;
+}
 
 #[derive(Deref)]
 pub struct MiBand<M: Model> {
-    #[deref]
-    device:      Device,
+    #[deref(forward)]
+    device:      Box<dyn Transport>,
     user:        User,
     device_info: Option<DeviceInfo>,
     model:       std::marker::PhantomData<M>,
     crc:         Crc<u8>,
 
     // pub commands: HashMap<String, fn>
-    pub characteristics: HashMap<::uuid::Uuid, Characteristic>,
+    pub characteristics: HashMap<::uuid::Uuid, Arc<dyn Characteristic>>,
 }
 
-impl<M: Model> AlertCapable for MiBand<M> {}
-impl HeartRateCapable for MiBand<OneS> {}
+impl<M: Model> HeartRateCapable for MiBand<M> {}
 
 #[async_trait]
-impl BluetoothDevice for MiBand<OneS> {
+impl<M: Model> BluetoothDevice for MiBand<M> {
     async fn connect(&mut self) -> Result<(), Error> {
         if !self.is_connected().await? {
             self.device.connect().await?;
@@ -110,70 +166,75 @@ impl BluetoothDevice for MiBand<OneS> {
 
         debug!("1");
 
-        if !self.characteristics.is_empty() {
-            self.characteristics.clear()
-        }
-
-        debug!("2");
+        self.characteristics = self.device.characteristics().await?;
 
-        for service in self.services().await? {
-            let characteristics = service.characteristics().await?;
+        // Later bands share services and manufacturer with 1S, but not its characteristics
+        let heartrate = if M::HEART_RATE { REQUIRED_HEART_RATE.as_slice() } else { &[] };
 
-            for c in characteristics.into_iter() {
-                let u = c.uuid().await?;
-                self.characteristics.insert(u, c);
-                debug!("Characteristic Found: {u}");
-            }
+        if let Some(uuid) = REQUIRED.iter().chain(heartrate).find(|uuid| self.try_characteristic(**uuid).is_none()) {
+            return Err(Error::Unsupported(format!("device without characteristic {uuid}")))
         }
 
-        debug!("3");
+        debug!("2");
 
         self.set_le_params(&LEParams::low_latency()).await?;
 
-        debug!("4");
+        debug!("3");
 
         let characteristic = &self.characteristics[&uuid::DATE_TIME];
         characteristic.read().await?;
 
-        debug!("5");
-
-        self.authenticate().await?;
-
-        debug!("6");
-
-        self.set_datetime(&Utc::now().into()).await?;
-
-        debug!("7");
+        debug!("4");
 
-        let today = Local::today().and_hms_opt(8, 30, 0).unwrap().into();
+        Ok(())
+    }
 
-        self.set_alarm(0, false, &today, false, AlarmFrequency::Everyday).await?;
-        self.set_alarm(1, false, &today, false, AlarmFrequency::Everyday).await?;
-        self.set_alarm(2, false, &today, false, AlarmFrequency::Everyday).await?;
+    async fn authenticate(&mut self) -> Result<(), Error> {
+        self.device_info = Some(self.device_info().await?);
 
+        tokio::time::timeout(AUTHENTICATION_TIMEOUT, self.introduce(&self.user))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
 
-        debug!("8");
+    async fn info(&self) -> Result<Info, Error> {
+        let device_info = match &self.device_info {
+            Some(device_info) => device_info.clone(),
+            None              => self.device_info().await?,
+        };
 
-        Ok(())
+        Ok(Info {
+            name:           self.device.name().await?.unwrap_or_default(),
+            hardware:       Some(device_info.hardware_version),
+            profile:        Some(device_info.profile_version),
+            firmware:       Some(device_info.firmware_version),
+            firmware_heart: Some(device_info.firmware_version_heart),
+        })
     }
 
-    fn characteristic(&self, uuid: ::uuid::Uuid) -> &Characteristic {
-        &self.characteristics[&uuid]
+    fn try_characteristic(&self, uuid: ::uuid::Uuid) -> Option<&Arc<dyn Characteristic>> {
+        self.characteristics.get(&uuid)
     }
 
     fn alert(&self)     -> Option<&(dyn Alert + Sync + Send)>     { Some(self) }
-    fn heartrate(&self) -> Option<&(dyn HeartRate + Sync + Send)> { Some(self) }
+    fn heartrate(&self) -> Option<&(dyn HeartRate + Sync + Send)> { M::HEART_RATE.then_some(self) }
     fn steps(&self)     -> Option<&(dyn Steps + Sync + Send)>     { Some(self) }
     fn battery(&self)   -> Option<&(dyn Battery + Sync + Send)>   { Some(self) }
+    fn activity(&self)  -> Option<&(dyn Activity + Sync + Send)>  { Some(self) }
+    fn alarm(&self)     -> Option<&(dyn Alarm + Sync + Send)>     { Some(self) }
+    fn wear(&self)      -> Option<&(dyn Wear + Sync + Send)>      { Some(self) }
+    fn clock(&self)     -> Option<&(dyn Clock + Sync + Send)>     { Some(self) }
+    fn profile(&self)   -> Option<&(dyn UserProfile + Sync + Send)> { Some(self) }
+    fn firmware(&self)  -> Option<&(dyn Firmware + Sync + Send)>    { Some(self) }
 }
 
-impl MiBand<OneS> {
-    pub fn boxed(device: Device) -> Box<dyn BluetoothDevice> {
+impl<M: Model> MiBand<M> {
+    pub fn boxed(device: Box<dyn Transport>, user: User) -> Box<dyn BluetoothDevice> {
         Box::from(Self {
             device,
-            user:            User::default(),
+            user,
             device_info:     None,
-            model:           std::marker::PhantomData::<OneS>,
+            model:           std::marker::PhantomData::<M>,
             crc:             Crc::<u8>::new(&CRC_8_MAXIM_DOW),
             characteristics: HashMap::new(),
         })
@@ -193,50 +254,14 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         self.characteristics[&uuid::DEVICE_NAME]
             .write_ext(&[0], WITH_RESPONSE)
             .await
-            .map_err(Into::into)
-    }
-
-    pub async fn set_wear_location(&self, location: WearLocation) -> Result<(), Error> {
-        let payload = [
-            match location {
-                WearLocation::Left  => 0,
-                WearLocation::Right => 1,
-                WearLocation::Neck  => 2,
-                _ => panic!("Wear location {:?} is not supported by the device", location)
-            }
-        ];
-
-        self.control_payload(control::WEAR_LOCATION, payload).await
-    }
-
-    async fn set_steps(&self, steps: u32) -> Result<(), Error> {
-        self.control_payload(control::SET_STEPS, steps.to_le_bytes()).await
-    }
-
-    pub async fn set_alarm(&self, id: u8, enabled: bool, dt: &DateTime, smart: bool, frequency: AlarmFrequency) -> Result<(), Error> {
-        let mut payload = [0; 10];
-        payload[0] = id;
-        payload[1] = enabled as u8;
-        payload[2..8].copy_from_slice(&datetime_as_bytes(dt));
-        payload[8] = smart as u8;
-        payload[9] = frequency.as_bits();
-        
-        self.control_payload(control::ALARM, payload).await
     }
 
-    pub async fn set_step_goal(&self, steps: u16) -> Result<(), Error> {
-        let mut payload = [0; 3];
-        payload[1..3].copy_from_slice(&steps.to_le_bytes());
-
-        self.control_payload(control::STEP_GOAL, payload).await
-    }
-
-    pub async fn authenticate(&mut self) -> Result<(), Error> {
+    // Band answers user info with the result of pairing, unfamiliar users have to be confirmed on the band
+    async fn introduce(&self, user: &User) -> Result<(), Error> {
         let notifications = self.notify().await?;
         pin_mut!(notifications);
 
-        self.device_info = Some(self.device_info().await?);
-        self.set_user(false).await?;
+        self.write_user(user, false).await?;
 
         loop {
             use notifications::auth::*;
@@ -244,9 +269,11 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
                 Some(CONFIRMED) => { debug!("Authentication: Confirmed ✓");  break Ok(()) },
                 Some(SUCCESS)   => { debug!("Authentication: Successful ✓"); break Ok(()) },
                 Some(AWAITING)  => { debug!("Authentication: Awaiting confirmation...");  },
-                Some(FAILED)    => { debug!("Authentication: Failed ✗");  },
+                Some(FAILED)    => { debug!("Authentication: Failed ✗"); break Err(Error::Authentication("rejected by device")) },
                 Some(TIMEOUT)   => { debug!("Authentication: Timeout "); },
-                _ => continue,
+                Some(_)         => continue,
+                // Stream ends along with the connection
+                None            => break Err(Error::Authentication("connection lost")),
             }
         }
     }
@@ -286,18 +313,16 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         // Ok(())
     // }
 
-    pub async fn notify_characteristics(&self) -> Result<impl Stream<Item = Vec<u8>>, Error> {
+    pub async fn notify_activity(&self) -> Result<impl Stream<Item = Vec<u8>>, Error> {
         self.characteristics[&uuid::ACTIVITY]
             .notify()
             .await
-            .map_err(Into::into)
     }
 
     pub async fn notify(&self) -> Result<impl Stream<Item = Vec<u8>>, Error> {
         self.characteristics[&uuid::NOTIFICATIONS]
             .notify()
             .await
-            .map_err(Into::into)
     }
 
     pub async fn device_info(&self) -> Result<DeviceInfo, Error> {
@@ -307,7 +332,7 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         DeviceInfo::try_from(payload.as_slice())
     }
 
-    async fn set_user(&self, auth: bool) -> Result<(), Error> {
+    async fn write_user(&self, user: &User, auth: bool) -> Result<(), Error> {
         let device_info = match &self.device_info {
             Some(device_info) => device_info,
             None => panic!("Couldn't find device info")
@@ -315,44 +340,17 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
 
         let characteristic = &self.characteristics[&uuid::USER_INFO];
 
-        let mut payload = self.user.to_bytes();
+        let mut payload = user.to_bytes();
         payload[8] = auth as u8;
         payload[9] = device_info.feature;
         payload[10] = device_info.appearance;
-        payload[19] = self.crc.checksum(&payload[..19]) ^ ADDRESS.last().unwrap();
+        payload[19] = self.crc.checksum(&payload[..19]) ^ self.device.address().0[5];
 
         characteristic.write_ext(&payload, WITH_RESPONSE).await?;
 
         Ok(())
     }
 
-    pub async fn datetime(&self) -> Result<DateTime, Error> {
-        let characteristic = &self.characteristics[&uuid::DATE_TIME];
-        let payload = characteristic.read().await?;
-
-        ensure_length!(payload, 6,
-           Utc.with_ymd_and_hms(
-                   payload[0] as i32 + 2000,
-                   payload[1] as u32,
-                   payload[2] as u32,
-                   payload[3] as u32,
-                   payload[4] as u32,
-                   payload[5] as u32).unwrap().into())
-    }
-
-    pub async fn set_datetime(&self, dt: &DateTime) -> Result<(), Error> {
-        let characteristic = &self.characteristics[&uuid::DATE_TIME];
-
-        let mut payload = [0xFF; 12];
-
-        payload[0..6].copy_from_slice(&datetime_as_bytes(dt));
-
-        characteristic
-            .write_ext(&payload, WITH_RESPONSE)
-            .await
-            .map_err(Into::into)
-    }
-
     pub async fn le_params(&self) -> Result<LEParams, Error> {
         let characteristic = &self.characteristics[&uuid::LE_PARAMS];
         let payload = characteristic.read().await?;
@@ -364,7 +362,6 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         self.characteristics[&uuid::LE_PARAMS]
             .write_ext(params.to_le_bytes(), WITH_RESPONSE)
             .await
-            .map_err(Into::into)
     }
 
     pub async fn factory_reset(&self) -> Result<(), Error> {
@@ -379,7 +376,6 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         self.characteristics[&uuid::CONTROL]
             .write_ext(&command.to_le_bytes(), WITH_RESPONSE)
             .await
-            .map_err(Into::into)
     }
 
     async fn control_payload<const N: usize>(&self, command: control::Command, data: [u8; N]) -> Result<(), Error> {
@@ -395,11 +391,26 @@ impl<M: Model> MiBand<M> where M: Sync + Send {
         self.characteristics[&uuid::CONTROL]
             .write_ext(&payload, WITH_RESPONSE)
             .await
-            .map_err(Into::into)
     }
 }
 
-fn datetime_as_bytes(dt: &DateTime) -> [u8; 6] {
+fn datetime_from_bytes(b: &[u8], mode: ClockMode) -> Result<DateTime, Error> {
+    let (year, month, day, hour, min, sec) = (b[0] as i32 + 2000, b[1] as u32, b[2] as u32, b[3] as u32, b[4] as u32, b[5] as u32);
+
+    let dt: Option<DateTime> = match mode {
+        ClockMode::Local => Local.with_ymd_and_hms(year, month, day, hour, min, sec).earliest().map(Into::into),
+        ClockMode::Utc   => Utc.with_ymd_and_hms(year, month, day, hour, min, sec).single().map(Into::into),
+    };
+
+    dt.ok_or(Error::Parse { expected: "valid date", position: 0, actual: b[0] })
+}
+
+fn datetime_as_bytes(dt: &DateTime, mode: ClockMode) -> [u8; 6] {
+    let dt = match mode {
+        ClockMode::Local => dt.with_timezone(&Local).naive_local(),
+        ClockMode::Utc   => dt.naive_utc(),
+    };
+
     [
         (dt.year() - 2000) as u8,
         dt.month() as u8,
@@ -410,15 +421,41 @@ fn datetime_as_bytes(dt: &DateTime) -> [u8; 6] {
     ]
 }
 
+// Band rings at the date it's given, time that has passed today is tomorrow's
+fn next_occurrence(time: NaiveTime, now: chrono::DateTime<Local>) -> chrono::DateTime<Local> {
+    let today = now.date_naive();
+    let date  = if time > now.time() { today } else { today.succ_opt().unwrap_or(today) };
+
+    date.and_time(time)
+        .and_local_timezone(Local)
+        .earliest()
+        .unwrap_or(now)
+}
+
+// level:    u8,
+// datetime: [u8; 6], of the last charge
+// charges:  u16,
+// status:   u8,
+fn battery_from_bytes(b: &[u8], mode: ClockMode) -> Result<BatteryInfo, Error> {
+    ensure_length!(b, 10,
+        BatteryInfo {
+            level:        b[0],
+            status:       BatteryStatus::try_from(b[9]).ok(),
+            // Zeroed on bands that were never charged
+            last_charged: datetime_from_bytes(&b[1..7], mode).ok(),
+            cycles:       Some(u16::from_le_bytes([b[7], b[8]])) })
+}
+
 #[async_trait]
 impl<M: Model> Battery for MiBand<M> where Self: Sync + Send {
     async fn battery_stream(&self) -> Result<Pin<Box<dyn Stream<Item = BatteryInfo> + Send>>, Error> {
+        let mode = self.user.clock;
+
         self.characteristics[&uuid::BATTERY_INFO] 
             .notify()
             .await
-            .map_err(Into::into)
-            .map(|stream| stream.map(|payload| {
-                     BatteryInfo::try_from(payload.as_slice()).expect("parsing battery info")
+            .map(|stream| stream.map(move |payload| {
+                     battery_from_bytes(&payload, mode).expect("parsing battery info")
                  }))
             .map(|stream| Box::pin(stream) as _)
     }
@@ -428,12 +465,12 @@ impl<M: Model> Battery for MiBand<M> where Self: Sync + Send {
 
         let payload = characteristic.read().await?;
 
-        BatteryInfo::try_from(payload.as_slice())
+        battery_from_bytes(&payload, self.user.clock)
     }
 }
 
 #[async_trait]
-impl<M: Model> Steps for MiBand<M> where Self: Sync + Send {
+impl<M: Model> Steps for MiBand<M> where M: Sync + Send {
     async fn steps(&self) -> Result<u32, Error> {
         let characteristic = &self.characteristics[&uuid::STEPS];
         let payload = characteristic.read().await?;
@@ -445,51 +482,437 @@ impl<M: Model> Steps for MiBand<M> where Self: Sync + Send {
     }
 
     async fn set_steps(&self, steps: u32) -> Result<(), Error> {
-        unimplemented!()
-        // self.control_payload(control::SET_STEPS, steps.to_le_bytes()).await
+        self.control_payload(control::SET_STEPS, steps.to_le_bytes()).await
     }
 
     async fn notify_steps(&self) -> Result<Pin<Box<dyn Stream<Item = u32> + Send>>, Error> {
         self.characteristics[&uuid::STEPS] 
             .notify()
             .await
-            .map_err(Into::into)
             .map(|stream| stream.map(|payload| {
                      payload.try_into().map(u32::from_le_bytes).expect("parsing steps")
                  }))
             .map(|stream| Box::pin(stream) as _)
     }
+
+    async fn set_goal(&self, steps: u32) -> Result<(), Error> {
+        let Ok(steps) = u16::try_from(steps) else {
+            return Err(Error::Unsupported(format!("step goal above {}", u16::MAX)))
+        };
+
+        let mut payload = [0; 3];
+        payload[1..3].copy_from_slice(&steps.to_le_bytes());
+
+        self.control_payload(control::STEP_GOAL, payload).await
+    }
 }
 
-struct User {
-    id:    u32,
-    alias: String,
-    bio:   Bio,
+#[async_trait]
+impl<M: Model> Alarm for MiBand<M> where M: Sync + Send {
+    fn alarm_slots(&self) -> u8 { 3 }
+
+    async fn set_alarm(&self, alarm: &AlarmSlot) -> Result<(), Error> {
+        if alarm.slot >= self.alarm_slots() {
+            return Err(Error::Unsupported(format!("alarm slot {}", alarm.slot)))
+        }
+
+        let dt = next_occurrence(alarm.time, Local::now()).into();
+
+        let mut payload = [0; 10];
+        payload[0] = alarm.slot;
+        payload[1] = alarm.enabled as u8;
+        payload[2..8].copy_from_slice(&datetime_as_bytes(&dt, self.user.clock));
+        payload[8] = alarm.smart as u8;
+        payload[9] = alarm.repeat;
+
+        self.control_payload(control::ALARM, payload).await
+    }
 }
 
-impl Default for User {
-    fn default() -> Self {
+#[async_trait]
+impl<M: Model> Alert for MiBand<M> where M: Sync + Send {
+    async fn alert(&self, level: AlertLevel) -> Result<(), Error> {
+        let payload = match level {
+            AlertLevel::Mild => [1],
+            AlertLevel::High => [2],
+        };
+
+        self.characteristics[&alert::uuid::ALERT_LEVEL]
+            .write(&payload)
+            .await
+    }
+
+    // Band only knows how to start and stop the motor, durations are kept by the host
+    async fn pattern(&self, pattern: &Pattern) -> Result<(), Error> {
+        if let Some(color) = pattern.color {
+            // LED channels range from 0 to 6, last byte makes it flash
+            let [r, g, b] = color.map(|c| (c as u16 * 6 / 255) as u8);
+            self.control_payload(control::COLOR, [r, g, b, 1]).await?;
+        }
+
+        for _ in 0..pattern.repeat {
+            self.control_payload(control::VIBRATE, [1]).await?;
+            tokio::time::sleep(pattern.on()).await;
+            self.control(control::STOP_VIBRATION).await?;
+            tokio::time::sleep(pattern.off()).await;
+        }
+
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), Error> {
+        self.control(control::STOP_VIBRATION).await
+    }
+}
+
+#[async_trait]
+impl<M: Model> UserProfile for MiBand<M> where M: Sync + Send {
+    async fn set_user(&self, user: &User) -> Result<(), Error> {
+        tokio::time::timeout(AUTHENTICATION_TIMEOUT, self.introduce(user))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+#[async_trait]
+impl<M: Model> Clock for MiBand<M> where M: Sync + Send {
+    async fn datetime(&self) -> Result<DateTime, Error> {
+        let characteristic = &self.characteristics[&uuid::DATE_TIME];
+        let payload = characteristic.read().await?;
+
+        ensure_length!(payload, 6, ())?;
+
+        datetime_from_bytes(&payload, self.user.clock)
+    }
+
+    async fn set_datetime(&self, dt: &DateTime) -> Result<(), Error> {
+        let characteristic = &self.characteristics[&uuid::DATE_TIME];
+
+        let mut payload = [0xFF; 12];
+
+        payload[0..6].copy_from_slice(&datetime_as_bytes(dt, self.user.clock));
+
+        characteristic
+            .write_ext(&payload, WITH_RESPONSE)
+            .await
+    }
+}
+
+#[async_trait]
+impl<M: Model> Wear for MiBand<M> where M: Sync + Send {
+    async fn set_wear_location(&self, location: WearLocation) -> Result<(), Error> {
+        let payload = [
+            match location {
+                WearLocation::Left  => 0,
+                WearLocation::Right => 1,
+                WearLocation::Neck  => 2,
+                _ => return Err(Error::Unsupported(format!("wear location {:?}", location)))
+            }
+        ];
+
+        self.control_payload(control::WEAR_LOCATION, payload).await
+    }
+}
+
+#[async_trait]
+impl<M: Model> Activity for MiBand<M> where M: Sync + Send {
+    async fn sync_activity(&self, acknowledged: &mut (dyn FnMut(Vec<ActivitySample>) + Send)) -> Result<(), Error> {
+        let notifications = self.notify_activity().await?;
+        pin_mut!(notifications);
+
+        self.control(control::COLLECT_DATA).await?;
+
+        let mut transfer = ActivityTransfer::new(self.user.clock, M::ACTIVITY_SAMPLE);
+        let mut synced = 0;
+
+        loop {
+            let payload = tokio::time::timeout(ACTIVITY_TIMEOUT, notifications.next())
+                .await
+                .map_err(|_| Error::Timeout)?
+                .ok_or(Error::Timeout)?;
+
+            match transfer.feed(&payload)? {
+                TransferStep::Continue => {},
+                TransferStep::Acknowledge(ack) => {
+                    self.control_payload(control::CONFIRM_ACTIVITY, ack).await?;
+
+                    // Band is free to forget them now, samples of a block it wasn't told about are sent again
+                    synced += transfer.samples.len();
+                    acknowledged(mem::take(&mut transfer.samples));
+                },
+                TransferStep::Finished => break,
+            }
+        }
+
+        self.control(control::STOP_SYNC).await?;
+
+        debug!("Synced {synced} minutes of activity");
+
+        Ok(())
+    }
+}
+
+// Activity arrives in blocks, every block starts with a header:
+//
+// kind:      u8,      1 - lengths are in minutes, 0 - in bytes
+// datetime:  [u8; 6], time of the first sample in block
+// total:     u16,     left to transfer
+// block:     u16,     in this block
+//
+// followed by a sample for every minute:
+//
+// category:  u8,
+// intensity: u8,
+// steps:     u8,
+// heartrate: u8,      only on models with heart rate sensor
+struct ActivityTransfer {
+    mode:        ClockMode,
+    datetime:    [u8; 6],
+    timestamp:   Option<DateTime>,
+    sample_size: usize,
+    remaining:   usize,
+    received:    u16,
+    buffer:      Vec<u8>,
+    samples:     Vec<ActivitySample>,
+}
+
+enum TransferStep {
+    Continue,
+    Acknowledge([u8; 8]),
+    Finished,
+}
+
+impl ActivityTransfer {
+    const HEADER_SIZE: usize = 11;
+
+    fn new(mode: ClockMode, sample_size: usize) -> Self {
         Self {
-            id:    141279967,
-            alias: "Morty".to_owned(),
-            bio:   Bio::default(),
+            mode,
+            datetime:  [0; 6],
+            timestamp: None,
+            sample_size,
+            remaining: 0,
+            received:  0,
+            buffer:    Vec::new(),
+            samples:   Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, payload: &[u8]) -> Result<TransferStep, Error> {
+        if self.remaining == 0 {
+            ensure_length!(payload, Self::HEADER_SIZE, ())?;
+
+            let total = u16::from_le_bytes([payload[7], payload[8]]);
+            let block = u16::from_le_bytes([payload[9], payload[10]]);
+
+            if total == 0 {
+                return Ok(TransferStep::Finished)
+            }
+
+            let unit = if payload[0] == 1 { self.sample_size } else { 1 };
+
+            self.datetime.copy_from_slice(&payload[1..7]);
+            self.timestamp = Some(datetime_from_bytes(&payload[1..7], self.mode)?);
+            self.remaining = block as usize * unit;
+            self.received  = 0;
+            self.buffer.clear();
+
+            return Ok(TransferStep::Continue)
+        }
+
+        let n = payload.len().min(self.remaining);
+        self.buffer.extend_from_slice(&payload[..n]);
+        self.remaining -= n;
+        self.received  += n as u16;
+
+        let Some(timestamp) = self.timestamp.as_mut() else {
+            return Err(Error::Nothing)
+        };
+
+        for sample in self.buffer.chunks_exact(self.sample_size) {
+            self.samples.push(ActivitySample {
+                timestamp: *timestamp,
+                category:  sample[0],
+                intensity: sample[1],
+                steps:     sample[2],
+                heartrate: sample.get(3).copied(),
+            });
+
+            *timestamp = (**timestamp + chrono::Duration::minutes(1)).into();
+        }
+
+        let consumed = self.buffer.len() - self.buffer.len() % self.sample_size;
+        self.buffer.drain(..consumed);
+
+        if self.remaining > 0 {
+            return Ok(TransferStep::Continue)
         }
+
+        let mut ack = [0; 8];
+        ack[..6].copy_from_slice(&self.datetime);
+        ack[6..].copy_from_slice(&self.received.to_le_bytes());
+
+        Ok(TransferStep::Acknowledge(ack))
     }
 }
 
-impl User {
-    const MAX_ALIAS_LENGTH: usize = 8;
+// Firmware of 1S bundles images for the band and its heart rate sensor:
+//
+// magic:    [u8; 4], "xuck"
+// ...
+// version:  u32, at 22
+// offset:   u32, at 26
+// length:   u32, at 30
+// ...
+// version:  u32, at 38, heart rate sensor
+// offset:   u32, at 42
+// length:   u32, at 46
+mod firmware {
+    pub const MAGIC:         &[u8] = b"xuck";
+    pub const HEADER_SIZE:   usize = 50;
+    pub const PARTS:         [(super::Target, usize); 2] = [(super::Target::Device, 22), (super::Target::Heartrate, 38)];
+
+    pub const HARDWARE:      u8 = 4; // 1S
+    pub const PACKET_SIZE:   usize = 20;
+    pub const SYNC_PACKETS:  usize = 50;
+}
+
+// Only 1S firmware is known, other models are turned down by hardware version
+#[async_trait]
+impl<M: Model> Firmware for MiBand<M> {
+    fn inspect(&self, b: &[u8]) -> Result<Vec<Part>, Error> {
+        let Some(device_info) = &self.device_info else {
+            return Err(Error::Authentication("device information is read once authenticated"))
+        };
+
+        if device_info.hardware_version != firmware::HARDWARE {
+            return Err(Error::Unsupported(format!("firmware update of hardware {}", device_info.hardware_version)))
+        }
+
+        if b.len() < firmware::HEADER_SIZE || !b.starts_with(firmware::MAGIC) {
+            return Err(Error::Firmware("unknown header, only 1S firmware is supported"))
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+
+        firmware::PARTS.iter().map(|&(target, at)| {
+            let version = Version(b[at..at + 4].try_into().unwrap());
+            let offset  = u32_at(at + 4) as usize;
+            let length  = u32_at(at + 8) as usize;
+
+            let Some(image) = b.get(offset..offset.saturating_add(length)).filter(|image| !image.is_empty()) else {
+                return Err(Error::Firmware("image is out of file bounds"))
+            };
+
+            if length > u16::MAX as usize {
+                return Err(Error::Firmware("image is too large"))
+            }
+
+            // Major version tells which chip image is built for
+            let (installed, major) = match target {
+                Target::Device    => (device_info.firmware_version, 4),
+                Target::Heartrate => (device_info.firmware_version_heart, 1),
+            };
+
+            if version[3] != major {
+                return Err(Error::Firmware("image is built for another hardware"))
+            }
+
+            Ok(Part {
+                target,
+                version,
+                offset,
+                length,
+                crc:      Crc::<u16>::new(&CRC_16_IBM_3740).checksum(image),
+                outdated: version != installed,
+            })
+        }).collect()
+    }
+
+    async fn flash(&self, b: &[u8], progress: &mut (dyn FnMut(Progress) + Send)) -> Result<(), Error> {
+        let parts = self.inspect(b)?;
+
+        let Some(device_info) = &self.device_info else {
+            return Err(Error::Authentication("device information is read once authenticated"))
+        };
+
+        for part in parts.iter().filter(|part| part.outdated) {
+            let installed = match part.target {
+                Target::Device    => device_info.firmware_version,
+                Target::Heartrate => device_info.firmware_version_heart,
+            };
+
+            debug!("Flashing {:?} firmware {} over {}", part.target, part.version, installed);
+
+            let notifications = self.notify().await?;
+            pin_mut!(notifications);
+
+            // Band expects checksum to be salted with the last two bytes of its address
+            let address = self.device.address().0;
+            let crc = part.crc ^ u16::from_le_bytes([address[4], address[5]]);
+
+            // target:    u8,
+            // length:    u16,
+            // installed: [u8; 4],
+            // version:   [u8; 4],
+            // crc:       u16,
+            // reboot:    u8,      rebooting is left for the end
+            let mut info = [0; 14];
+            info[0] = part.target.into();
+            info[1..3].copy_from_slice(&(part.length as u16).to_le_bytes());
+            info[3..7].copy_from_slice(&*installed);
+            info[7..11].copy_from_slice(&*part.version);
+            info[11..13].copy_from_slice(&crc.to_le_bytes());
+
+            self.control_payload(control::FIRMWARE_INFO, info).await?;
+
+            let image = &b[part.offset..part.offset + part.length];
+            let characteristic = &self.characteristics[&uuid::FIRMWARE];
+
+            for (i, packet) in image.chunks(firmware::PACKET_SIZE).enumerate() {
+                characteristic.write_ext(packet, WITHOUT_RESPONSE).await?;
+
+                // Band has to catch up every now and then, response to sync is the only flow control there is
+                if (i + 1) % firmware::SYNC_PACKETS == 0 {
+                    self.control(control::SYNC).await?;
+                    progress(Progress { target: part.target, sent: (i + 1) * firmware::PACKET_SIZE, total: part.length });
+                }
+            }
 
+            self.control(control::SYNC).await?;
+            progress(Progress { target: part.target, sent: part.length, total: part.length });
+
+            loop {
+                let payload = tokio::time::timeout(FIRMWARE_TIMEOUT, notifications.next())
+                    .await
+                    .map_err(|_| Error::Timeout)?
+                    .ok_or(Error::Timeout)?;
+
+                match payload.as_slice() {
+                    notifications::firmware::SUCCESS => break,
+                    notifications::firmware::FAILED  => return Err(Error::Firmware("rejected by device")),
+                    _ => continue,
+                }
+            }
+        }
+
+        self.reboot().await
+    }
+}
+
+const MAX_ALIAS_LENGTH: usize = 8;
+
+impl User {
     fn to_bytes(&self) -> [u8; 20] {
         let mut b = [0u8; 20];
 
         b[0..4].copy_from_slice(&self.id.to_le_bytes());
         b[4] = self.bio.sex.into();
-        b[5] = self.bio.age;
+        b[5] = self.bio.age();
         b[6] = self.bio.height;
         b[7] = self.bio.weight;
 
-        let i = std::cmp::min(self.alias.len(), Self::MAX_ALIAS_LENGTH);
+        let i = std::cmp::min(self.alias.len(), MAX_ALIAS_LENGTH);
         b[11..11+i].copy_from_slice(&self.alias.as_bytes()[..i]);
 
         b
@@ -517,6 +940,7 @@ impl From<Sex> for u8 {
     }
 }
 
+#[derive(Clone)]
 pub struct DeviceInfo {
     pub id:                     u32,
     pub feature:                u8,
@@ -549,22 +973,6 @@ impl fmt::Display for Version {
     }
 }
 
-impl TryFrom<&[u8]> for BatteryInfo {
-    type Error = Error;
-
-    // level:    u8,
-    // datetime: [u8; 6],
-    // charges:  u16,
-    // status:   u8,
-
-    fn try_from(b: &[u8]) -> Result<Self, Self::Error> {
-        ensure_length!(b, 10,
-                Self {
-                level:    b[0],
-                status:   BatteryStatus::try_from(b[9]).ok() })
-    }
-}
-
 impl TryFrom<u8> for BatteryStatus {
     type Error = Error;
 
@@ -627,3 +1035,171 @@ impl TryFrom<&[u8]> for LEParams {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bio::Bio;
+    use crate::devices::simulator::Simulator;
+
+    async fn band() -> Box<dyn BluetoothDevice> {
+        let user = User { id: 42, alias: "test".to_owned(), bio: Bio::default(), clock: ClockMode::Utc };
+        let mut band = MiBand::<OneS>::boxed(Box::new(Simulator::new(bluer::Address::new([0x8A, 0x0F, 0x10, 0, 0x2B, 1]))), user);
+
+        band.connect().await.unwrap();
+        band.authenticate().await.unwrap();
+        band
+    }
+
+    // Header and images of both chips, versions are newer than the ones simulator reports
+    fn firmware_file(length: usize) -> Vec<u8> {
+        let mut b = vec![0; firmware::HEADER_SIZE];
+        b[..4].copy_from_slice(firmware::MAGIC);
+
+        for (i, (at, major)) in [(22, 4), (38, 1)].into_iter().enumerate() {
+            let offset = firmware::HEADER_SIZE + i * length;
+            b[at..at + 4].copy_from_slice(&[0, 0, 20, major]);
+            b[at + 4..at + 8].copy_from_slice(&(offset as u32).to_le_bytes());
+            b[at + 8..at + 12].copy_from_slice(&(length as u32).to_le_bytes());
+        }
+
+        b.extend((0..2 * length).map(|i| i as u8));
+        b
+    }
+
+    #[tokio::test]
+    async fn info() {
+        let band = band().await;
+        let info = band.info().await.unwrap();
+
+        assert_eq!(info.name, "MI1S");
+        assert_eq!(info.hardware, Some(4));
+        assert_eq!(info.firmware.map(|v| v.to_string()).as_deref(), Some("4.15.12.10"));
+    }
+
+    #[tokio::test]
+    async fn heartrate_only_on_1s() {
+        let user = User { id: 42, alias: "test".to_owned(), bio: Bio::default(), clock: ClockMode::Utc };
+        let mut one = MiBand::<One>::boxed(Box::new(Simulator::new(bluer::Address::new([0x8A, 0x0F, 0x10, 0, 0, 2]))), user);
+
+        one.connect().await.unwrap();
+
+        assert!(one.heartrate().is_none());
+        assert!(band().await.heartrate().is_some());
+    }
+
+    #[tokio::test]
+    async fn battery() {
+        let band = band().await;
+        let battery = band.battery().unwrap().battery().await.unwrap();
+
+        assert_eq!(battery.level, 80);
+        assert_eq!(battery.status, Some(BatteryStatus::NotCharging));
+        assert_eq!(battery.cycles, Some(12));
+        assert!(battery.last_charged.is_some());
+    }
+
+    #[tokio::test]
+    async fn steps() {
+        let band = band().await;
+        let steps = band.steps().unwrap();
+
+        steps.set_steps(4242).await.unwrap();
+        assert_eq!(steps.steps().await.unwrap(), 4242);
+
+        assert!(matches!(steps.set_goal(70000).await, Err(Error::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn activity() {
+        let band = band().await;
+        let mut samples = Vec::new();
+
+        band.activity().unwrap().sync_activity(&mut |block| samples.extend(block)).await.unwrap();
+
+        // Simulator piles up half an hour before the first sync
+        assert_eq!(samples.len(), 30);
+        assert!(samples.iter().all(|s| s.heartrate.is_some()));
+        assert!(samples.windows(2).all(|w| *w[1].timestamp - *w[0].timestamp == chrono::Duration::minutes(1)));
+    }
+
+    #[tokio::test]
+    async fn firmware() {
+        let band = band().await;
+        let file = firmware_file(1500);
+
+        let parts = band.firmware().unwrap().inspect(&file).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| part.outdated && part.length == 1500));
+
+        let mut progress = Vec::new();
+        band.firmware().unwrap().flash(&file, &mut |p| progress.push((p.target, p.sent))).await.unwrap();
+
+        assert_eq!(progress, [(Target::Device, 1000), (Target::Device, 1500), (Target::Heartrate, 1000), (Target::Heartrate, 1500)]);
+
+        // Band reboots once flashed
+        assert!(!band.is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn firmware_mismatch() {
+        let band = band().await;
+
+        let mut file = firmware_file(100);
+        file[25] = 5;
+
+        assert!(matches!(band.firmware().unwrap().inspect(&file), Err(Error::Firmware(_))));
+        assert!(matches!(band.firmware().unwrap().inspect(b"xuck"), Err(Error::Firmware(_))));
+    }
+
+    #[test]
+    fn alarm_rolls_over() {
+        let now = Local.with_ymd_and_hms(2024, 3, 1, 22, 0, 0).unwrap();
+
+        let morning = next_occurrence(NaiveTime::from_hms_opt(7, 0, 0).unwrap(), now);
+        assert_eq!(morning.naive_local().to_string(), "2024-03-02 07:00:00");
+
+        let later = next_occurrence(NaiveTime::from_hms_opt(23, 30, 0).unwrap(), now);
+        assert_eq!(later.naive_local().to_string(), "2024-03-01 23:30:00");
+
+        // Right now has already passed by the time band gets it
+        let now = next_occurrence(NaiveTime::from_hms_opt(22, 0, 0).unwrap(), now);
+        assert_eq!(now.naive_local().to_string(), "2024-03-02 22:00:00");
+    }
+
+    #[test]
+    fn activity_transfer() {
+        let mut transfer = ActivityTransfer::new(ClockMode::Utc, OneS::ACTIVITY_SAMPLE);
+
+        // Two minutes with heart rate, split between packets
+        let header = [1, 24, 3, 1, 8, 30, 0, 2, 0, 2, 0];
+        assert!(matches!(transfer.feed(&header), Ok(TransferStep::Continue)));
+        assert!(matches!(transfer.feed(&[1, 10, 20, 70, 1]), Ok(TransferStep::Continue)));
+        assert_eq!(transfer.samples.len(), 1);
+
+        let Ok(TransferStep::Acknowledge(ack)) = transfer.feed(&[11, 21, 71]) else { panic!("block isn't acknowledged") };
+        assert_eq!(ack, [24, 3, 1, 8, 30, 0, 8, 0]);
+
+        let steps: Vec<_> = transfer.samples.iter().map(|s| (s.steps, s.heartrate)).collect();
+        assert_eq!(steps, [(20, Some(70)), (21, Some(71))]);
+        assert_eq!(transfer.samples[1].timestamp.to_rfc3339(), "2024-03-01T08:31:00+00:00");
+
+        assert!(matches!(transfer.feed(&[0; 11]), Ok(TransferStep::Finished)));
+        assert!(matches!(transfer.feed(&[0; 4]), Err(Error::Length { .. })));
+    }
+
+    #[test]
+    fn activity_transfer_in_bytes() {
+        let mut transfer = ActivityTransfer::new(ClockMode::Utc, One::ACTIVITY_SAMPLE);
+
+        // Two minutes without heart rate, block length in bytes
+        let header = [0, 24, 3, 1, 8, 30, 0, 6, 0, 6, 0];
+        assert!(matches!(transfer.feed(&header), Ok(TransferStep::Continue)));
+
+        let Ok(TransferStep::Acknowledge(ack)) = transfer.feed(&[1, 10, 20, 1, 11, 21]) else { panic!("block isn't acknowledged") };
+        assert_eq!(ack[6..], [6, 0]);
+
+        let steps: Vec<_> = transfer.samples.iter().map(|s| (s.steps, s.heartrate)).collect();
+        assert_eq!(steps, [(20, None), (21, None)]);
+    }
+}
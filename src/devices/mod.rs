@@ -4,13 +4,15 @@ use std::fmt::Debug;
 
 use chrono::{Local, Utc};
 use derive_more::{Deref, From};
+use serde::{Deserialize, Serialize};
 
 automod::dir!(pub "src/devices");
 
-#[derive(Deref)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deref)]
 pub struct Version([u8; 4]);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum WearLocation {
     Left,
     Right,
@@ -18,7 +20,7 @@ pub enum WearLocation {
     Pocket,
 }
 
-#[derive(Debug, Deref, From)]
+#[derive(Debug, Clone, Copy, Deref, From)]
 pub struct DateTime(chrono::DateTime<Utc>);
 
 impl From<chrono::DateTime<Local>> for DateTime {
@@ -27,6 +29,16 @@ impl From<chrono::DateTime<Local>> for DateTime {
     }
 }
 
+/// What a device tells about itself, only some know their versions.
+#[derive(Debug, Clone, Default)]
+pub struct Info {
+    pub name:           String,
+    pub hardware:       Option<u8>,
+    pub profile:        Option<Version>,
+    pub firmware:       Option<Version>,
+    pub firmware_heart: Option<Version>,
+}
+
 trait Status {}
 
 enum Authorized {}
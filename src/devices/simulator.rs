@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bluer::Address;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike, Utc};
+use crc::{Crc, CRC_16_IBM_3740};
+use futures::{StreamExt, stream};
+use log::debug;
+use rand::Rng;
+use tokio::sync::broadcast;
+use ::uuid::Uuid;
+
+use super::capabilities::{alert, heartrate};
+use super::miband::{control, notifications, uuid};
+use super::transport::{Characteristic, Notifications, Transport, WriteType};
+use crate::Error;
+
+const TICK: Duration = Duration::from_secs(5);
+
+// Band goes on the charger when it's this low and comes off once full
+const BATTERY_EMPTY: u8 = 5;
+const BATTERY_LOW:   u8 = 10;
+
+const CHARACTERISTICS: [Uuid; 14] = [
+    uuid::DEVICE_INFO,
+    uuid::DEVICE_NAME,
+    uuid::NOTIFICATIONS,
+    uuid::USER_INFO,
+    uuid::CONTROL,
+    uuid::STEPS,
+    uuid::ACTIVITY,
+    uuid::FIRMWARE,
+    uuid::LE_PARAMS,
+    uuid::DATE_TIME,
+    uuid::BATTERY_INFO,
+    alert::uuid::ALERT_LEVEL,
+    heartrate::uuid::HEART_RATE_MEASUREMENT,
+    heartrate::uuid::HEART_RATE_CONTROL_POINT,
+];
+
+// MiBand 1S that only exists in memory, it knows just enough of the protocol to drive the daemon.
+// Steps go up on their own, activity piles up between syncs, pairing always succeeds.
+pub struct Simulator {
+    address: Address,
+    band:    Arc<Band>,
+}
+
+struct Band {
+    address:   Address,
+    connected: AtomicBool,
+    state:     Mutex<State>,
+    notifiers: HashMap<Uuid, broadcast::Sender<Vec<u8>>>,
+}
+
+struct State {
+    steps:      u32,
+    battery:    u8,
+    charging:   bool,
+    charged:    NaiveDateTime,    // last time, band clock
+    cycles:     u16,
+    offset:     chrono::Duration, // band clock ahead of UTC
+    le_params:  Vec<u8>,
+    continuous: bool,             // heart rate
+    last_sync:  NaiveDateTime,
+    firmware:   Option<Upload>,
+}
+
+struct Upload {
+    length: usize,
+    crc:    u16, // salted with the address
+    image:  Vec<u8>,
+}
+
+struct SimulatedCharacteristic {
+    uuid: Uuid,
+    band: Arc<Band>,
+}
+
+impl Simulator {
+    pub fn new(address: Address) -> Self {
+        let notifiers = CHARACTERISTICS.iter()
+            .map(|uuid| (*uuid, broadcast::channel(64).0))
+            .collect();
+
+        let state = State {
+            steps:      0,
+            battery:    80,
+            charging:   false,
+            charged:    Utc::now().naive_utc() - chrono::Duration::days(3),
+            cycles:     12,
+            offset:     chrono::Duration::zero(),
+            le_params:  vec![0; 12],
+            continuous: false,
+            last_sync:  Utc::now().naive_utc() - chrono::Duration::minutes(30),
+            firmware:   None,
+        };
+
+        let band = Arc::new(Band { address, connected: AtomicBool::new(false), state: Mutex::new(state), notifiers });
+
+        tokio::spawn(tick(Arc::downgrade(&band)));
+
+        Self { address, band }
+    }
+}
+
+// Band lives its own life in between requests
+async fn tick(band: Weak<Band>) {
+    let mut interval = tokio::time::interval(TICK);
+
+    loop {
+        interval.tick().await;
+
+        let Some(band) = band.upgrade() else { return };
+
+        if !band.connected.load(Ordering::Relaxed) {
+            continue
+        }
+
+        let (steps, continuous, battery) = {
+            let mut state = band.state.lock().unwrap();
+            state.steps += rand::thread_rng().gen_range(0..30);
+            (state.steps, state.continuous, state.drain())
+        };
+
+        band.notify(uuid::STEPS, steps.to_le_bytes().to_vec());
+
+        if let Some(battery) = battery {
+            band.notify(uuid::BATTERY_INFO, battery);
+        }
+
+        if continuous {
+            band.notify(heartrate::uuid::HEART_RATE_MEASUREMENT, vec![0, rand::thread_rng().gen_range(60..90)]);
+        }
+    }
+}
+
+impl State {
+    // Battery info when it changed
+    fn drain(&mut self) -> Option<Vec<u8>> {
+        if self.charging {
+            self.battery = (self.battery + 5).min(100);
+            self.charging = self.battery < 100;
+        } else if self.battery <= BATTERY_EMPTY {
+            self.charging = true;
+            self.charged  = Utc::now().naive_utc() + self.offset;
+            self.cycles  += 1;
+        } else if rand::thread_rng().gen_ratio(1, 4) {
+            self.battery -= 1;
+        } else {
+            return None
+        }
+
+        Some(self.battery_info())
+    }
+
+    fn battery_info(&self) -> Vec<u8> {
+        // low, charging, not charging, full
+        let status = match (self.charging, self.battery) {
+            (true, _)                      => 2,
+            (false, 100)                   => 4, // until it drains
+            (false, b) if b <= BATTERY_LOW => 1,
+            (false, _)                     => 3,
+        };
+
+        let mut b = vec![self.battery];
+        b.extend_from_slice(&datetime_as_bytes(&self.charged));
+        b.extend_from_slice(&self.cycles.to_le_bytes());
+        b.push(status);
+        b
+    }
+}
+
+impl Band {
+    fn notify(&self, uuid: Uuid, payload: Vec<u8>) {
+        // Nobody listening is fine
+        let _ = self.notifiers[&uuid].send(payload);
+    }
+
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc() + self.state.lock().unwrap().offset
+    }
+
+    fn read(&self, uuid: Uuid) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+
+        match uuid {
+            uuid::DEVICE_INFO => {
+                let mut b = vec![0; 20];
+                b[0..4].copy_from_slice(&0x1f2e3d4c_u32.to_be_bytes());
+                b[6] = 4;                                  // 1S
+                b[12..16].copy_from_slice(&[10, 12, 15, 4]); // 4.15.12.10
+                b[16..20].copy_from_slice(&[22, 76, 3, 1]);  // 1.3.76.22
+                b
+            }
+            uuid::DEVICE_NAME  => [&[0, 0, 0], b"MI1S".as_slice()].concat(),
+            uuid::DATE_TIME    => {
+                let mut b = datetime_as_bytes(&(Utc::now().naive_utc() + state.offset)).to_vec();
+                b.extend_from_slice(&[0xFF; 6]);
+                b
+            }
+            uuid::LE_PARAMS    => state.le_params.clone(),
+            uuid::STEPS        => state.steps.to_le_bytes().to_vec(),
+            uuid::BATTERY_INFO => state.battery_info(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn write(&self, uuid: Uuid, value: &[u8]) {
+        debug!("Simulator: {uuid} <- {value:?}");
+
+        match uuid {
+            uuid::USER_INFO => self.notify(uuid::NOTIFICATIONS, notifications::auth::SUCCESS.to_vec()),
+            uuid::DATE_TIME => {
+                if let Some(dt) = datetime_from_bytes(value) {
+                    self.state.lock().unwrap().offset = dt - Utc::now().naive_utc();
+                }
+            }
+            uuid::LE_PARAMS => self.state.lock().unwrap().le_params = value.to_vec(),
+            uuid::FIRMWARE  => {
+                if let Some(upload) = &mut self.state.lock().unwrap().firmware {
+                    upload.image.extend_from_slice(value);
+                }
+            }
+            uuid::CONTROL => self.control(value),
+            heartrate::uuid::HEART_RATE_CONTROL_POINT => match value {
+                [0x15, 0x2, _]      => self.notify(heartrate::uuid::HEART_RATE_MEASUREMENT, vec![0, 72]),
+                [0x15, 0x1, enable] => self.state.lock().unwrap().continuous = *enable != 0,
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+
+    fn control(&self, value: &[u8]) {
+        let Some(&command) = value.first() else { return };
+
+        match command {
+            control::COLLECT_DATA => self.activity(),
+            control::CONFIRM_ACTIVITY => self.notify(uuid::ACTIVITY, vec![0; 11]),
+            control::FIRMWARE_INFO if value.len() >= 14 => {
+                let length = u16::from_le_bytes([value[2], value[3]]) as usize;
+                let crc    = u16::from_le_bytes([value[12], value[13]]);
+                self.state.lock().unwrap().firmware = Some(Upload { length, crc, image: Vec::new() });
+            }
+            control::SYNC => {
+                let mut state = self.state.lock().unwrap();
+
+                if state.firmware.as_ref().is_some_and(|upload| upload.image.len() >= upload.length) {
+                    let upload = state.firmware.take().unwrap();
+
+                    // Salted with the last two bytes of address
+                    let salt = u16::from_le_bytes([self.address.0[4], self.address.0[5]]);
+                    let crc  = Crc::<u16>::new(&CRC_16_IBM_3740).checksum(&upload.image) ^ salt;
+
+                    let result = if crc == upload.crc { notifications::firmware::SUCCESS } else { notifications::firmware::FAILED };
+                    self.notify(uuid::NOTIFICATIONS, result.to_vec());
+                }
+            }
+            control::SET_STEPS if value.len() >= 5 => {
+                self.state.lock().unwrap().steps = u32::from_le_bytes(value[1..5].try_into().unwrap());
+            }
+            control::REBOOT => self.connected.store(false, Ordering::Relaxed),
+            _ => {},
+        }
+    }
+
+    // Every minute since the last sync in a single block counted in minutes, each with heart rate like 1S does
+    fn activity(&self) {
+        let now = self.now();
+
+        let minutes = {
+            let mut state = self.state.lock().unwrap();
+            let minutes = (now - state.last_sync).num_minutes().clamp(0, 60) as u16;
+            state.last_sync = now;
+            minutes
+        };
+
+        if minutes == 0 {
+            return self.notify(uuid::ACTIVITY, vec![0; 11])
+        }
+
+        let mut header = vec![1];
+        header.extend_from_slice(&datetime_as_bytes(&(now - chrono::Duration::minutes(minutes as i64))));
+        header.extend_from_slice(&minutes.to_le_bytes());
+        header.extend_from_slice(&minutes.to_le_bytes());
+        self.notify(uuid::ACTIVITY, header);
+
+        let mut rng = rand::thread_rng();
+        let samples: Vec<u8> = (0..minutes)
+            .flat_map(|_| [1, rng.gen_range(0..100), rng.gen_range(0..30), rng.gen_range(60..90)])
+            .collect();
+
+        for packet in samples.chunks(20) {
+            self.notify(uuid::ACTIVITY, packet.to_vec());
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for Simulator {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn name(&self) -> Result<Option<String>, Error> {
+        Ok(Some("MI1S".to_owned()))
+    }
+
+    async fn connect(&self) -> Result<(), Error> {
+        self.band.connected.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool, Error> {
+        Ok(self.band.connected.load(Ordering::Relaxed))
+    }
+
+    async fn characteristics(&self) -> Result<HashMap<Uuid, Arc<dyn Characteristic>>, Error> {
+        Ok(CHARACTERISTICS.iter()
+            .map(|&uuid| (uuid, Arc::new(SimulatedCharacteristic { uuid, band: self.band.clone() }) as _))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Characteristic for SimulatedCharacteristic {
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.band.read(self.uuid))
+    }
+
+    async fn write_ext(&self, value: &[u8], _write: WriteType) -> Result<(), Error> {
+        self.band.write(self.uuid, value);
+        Ok(())
+    }
+
+    async fn notify(&self) -> Result<Notifications, Error> {
+        let rx = self.band.notifiers[&self.uuid].subscribe();
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(payload) => return Some((payload, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed)    => return None,
+                }
+            }
+        }).boxed())
+    }
+
+    async fn can_notify(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
+
+fn datetime_as_bytes(dt: &NaiveDateTime) -> [u8; 6] {
+    [(dt.year() - 2000) as u8, dt.month() as u8, dt.day() as u8, dt.hour() as u8, dt.minute() as u8, dt.second() as u8]
+}
+
+fn datetime_from_bytes(b: &[u8]) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(*b.first()? as i32 + 2000, *b.get(1)? as u32, *b.get(2)? as u32)?
+        .and_hms_opt(*b.get(3)? as u32, *b.get(4)? as u32, *b.get(5)? as u32)
+}
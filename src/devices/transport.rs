@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bluer::Address;
+use bluer::gatt::WriteOp;
+use bluer::gatt::remote::CharacteristicWriteRequest;
+use futures::{Stream, StreamExt};
+use log::debug;
+use uuid::Uuid;
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteType {
+    Request, // with response
+    Command, // without response
+}
+
+pub type Notifications = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+
+// Devices only talk through these, so they don't care whether BlueZ is on the other end
+#[async_trait]
+pub trait Transport: Sync + Send {
+    fn address(&self) -> Address;
+    async fn name(&self) -> Result<Option<String>, Error>;
+    async fn connect(&self) -> Result<(), Error>;
+    async fn is_connected(&self) -> Result<bool, Error>;
+    async fn characteristics(&self) -> Result<HashMap<Uuid, Arc<dyn Characteristic>>, Error>;
+}
+
+#[async_trait]
+pub trait Characteristic: Sync + Send {
+    async fn read(&self) -> Result<Vec<u8>, Error>;
+    async fn write_ext(&self, value: &[u8], write: WriteType) -> Result<(), Error>;
+    async fn notify(&self) -> Result<Notifications, Error>;
+    async fn can_notify(&self) -> Result<bool, Error>;
+
+    async fn write(&self, value: &[u8]) -> Result<(), Error> {
+        self.write_ext(value, WriteType::Request).await
+    }
+}
+
+#[async_trait]
+impl Transport for bluer::Device {
+    fn address(&self) -> Address {
+        bluer::Device::address(self)
+    }
+
+    async fn name(&self) -> Result<Option<String>, Error> {
+        bluer::Device::name(self).await.map_err(Into::into)
+    }
+
+    async fn connect(&self) -> Result<(), Error> {
+        bluer::Device::connect(self).await.map_err(Into::into)
+    }
+
+    async fn is_connected(&self) -> Result<bool, Error> {
+        bluer::Device::is_connected(self).await.map_err(Into::into)
+    }
+
+    async fn characteristics(&self) -> Result<HashMap<Uuid, Arc<dyn Characteristic>>, Error> {
+        let mut characteristics: HashMap<Uuid, Arc<dyn Characteristic>> = HashMap::new();
+
+        for service in self.services().await? {
+            for c in service.characteristics().await? {
+                let u = c.uuid().await?;
+                characteristics.insert(u, Arc::new(c));
+                debug!("Characteristic Found: {u}");
+            }
+        }
+
+        Ok(characteristics)
+    }
+}
+
+#[async_trait]
+impl Characteristic for bluer::gatt::remote::Characteristic {
+    async fn read(&self) -> Result<Vec<u8>, Error> {
+        bluer::gatt::remote::Characteristic::read(self).await.map_err(Into::into)
+    }
+
+    async fn write_ext(&self, value: &[u8], write: WriteType) -> Result<(), Error> {
+        let request = CharacteristicWriteRequest {
+            op_type: match write {
+                WriteType::Request => WriteOp::Request,
+                WriteType::Command => WriteOp::Command,
+            },
+            ..Default::default()
+        };
+
+        bluer::gatt::remote::Characteristic::write_ext(self, value, &request).await.map_err(Into::into)
+    }
+
+    async fn notify(&self) -> Result<Notifications, Error> {
+        bluer::gatt::remote::Characteristic::notify(self).await
+            .map(|stream| stream.boxed())
+            .map_err(Into::into)
+    }
+
+    async fn can_notify(&self) -> Result<bool, Error> {
+        Ok(self.flags().await?.notify)
+    }
+}
@@ -10,12 +10,49 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Dbus(#[from] dbus::Error),
+
+    #[error("invalid configuration: {0}")]
+    Config(#[from] toml::de::Error),
+
     #[error("invalid byte at {position:#x} (expected {expected}, got {actual})")]
     Parse { expected: &'static str, position: usize, actual: u8 },
 
     #[error("invalid data length (expected {expected}, got {actual})")]
     Length { expected: usize, actual: usize },
 
+    #[error("device didn't respond in time")]
+    Timeout,
+
+    #[error("not supported by device: {0}")]
+    Unsupported(String),
+
+    #[error("invalid firmware: {0}")]
+    Firmware(&'static str),
+
+    #[error("no such device")]
+    NoDevice,
+
+    #[error("device is busy")]
+    Busy,
+
+    #[error("authentication failed: {0}")]
+    Authentication(&'static str),
+
+    #[error("device is not connected")]
+    NotConnected,
+
+    #[error("invalid range ({from} isn't before {to})")]
+    InvalidRange { from: i64, to: i64 },
+
+    // Answer of the daemon to a client
+    #[error("daemon refused: {reason}")]
+    Refused { code: u8, reason: String },
+
     // #[error("command not found - `{0}`")]
     // CommandNotFound(Command),
 
@@ -24,6 +61,29 @@ pub enum Error {
 }
 
 impl Error {
+    /// Stable number of the variant, sent to clients along with the description.
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Nothing              => 0,
+            Error::Bluetooth(_)         => 1,
+            Error::Io(_)                => 2,
+            Error::Json(_)              => 3,
+            Error::Dbus(_)              => 4,
+            Error::Config(_)            => 5,
+            Error::Parse { .. }         => 6,
+            Error::Length { .. }        => 7,
+            Error::Timeout              => 8,
+            Error::Unsupported(_)       => 9,
+            Error::Firmware(_)          => 10,
+            Error::NoDevice             => 11,
+            Error::Busy                 => 12,
+            Error::Authentication(_)    => 13,
+            Error::NotConnected         => 14,
+            Error::InvalidRange { .. }  => 15,
+            Error::Refused { code, .. } => *code,
+        }
+    }
+
     pub fn vec_len<T>(v: Vec<u8>) -> Self {
         Error::Length { expected: std::mem::size_of::<T>(), actual: v.len() }
     }
@@ -40,7 +100,7 @@ impl Debug for Error {
                     _ => e.fmt(f)
                 }
             }
-            _ => self.fmt(f)
+            _ => write!(f, "{self}")
         }
     }
 }
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bluer::Address;
+use tokio::time::Instant;
+
+use crate::ipc::Message;
+
+/// Messages a client asked for, of one type or any, about one device or any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription {
+    pub kind:     Option<u8>,
+    pub device:   Option<Address>,
+    pub interval: Duration, // at most one message per device this often, zero for all of them
+}
+
+// What a client gets to hear, everything until it says otherwise
+pub struct Filter {
+    subscriptions: Vec<(Subscription, HashMap<Address, Instant>)>, // last forwarded about each device
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        let everything = Subscription { kind: None, device: None, interval: Duration::ZERO };
+
+        Self { subscriptions: vec![(everything, HashMap::new())] }
+    }
+}
+
+impl Subscription {
+    pub fn matches(&self, device: Address, message: &Message) -> bool {
+        self.kind.is_none_or(|kind| kind == message.id()) && self.device.is_none_or(|d| d == device)
+    }
+
+    // Type weighs more than device, so heart rate of any device beats everything of one
+    fn specificity(&self) -> u8 {
+        (self.kind.is_some() as u8) << 1 | self.device.is_some() as u8
+    }
+}
+
+impl Filter {
+    /// Replaces the subscription to the same type and device, if there is one.
+    pub fn subscribe(&mut self, subscription: Subscription) {
+        self.unsubscribe(subscription.kind, subscription.device);
+        self.subscriptions.push((subscription, HashMap::new()));
+    }
+
+    pub fn unsubscribe(&mut self, kind: Option<u8>, device: Option<Address>) {
+        self.subscriptions.retain(|(s, _)| (s.kind, s.device) != (kind, device));
+    }
+
+    /// Whether the message goes through, decided by the most specific subscription it matches.
+    pub fn pass(&mut self, device: Address, message: &Message) -> bool {
+        let Some((subscription, last)) = self.subscriptions.iter_mut()
+            .filter(|(s, _)| s.matches(device, message))
+            .max_by_key(|(s, _)| s.specificity()) else {
+            return false
+        };
+
+        if subscription.interval.is_zero() {
+            return true
+        }
+
+        let now = Instant::now();
+
+        match last.get(&device) {
+            Some(at) if now.duration_since(*at) < subscription.interval => false,
+            _ => {
+                last.insert(device, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Address = Address::new([1; 6]);
+    const B: Address = Address::new([2; 6]);
+
+    #[test]
+    fn everything_by_default() {
+        let mut filter = Filter::default();
+
+        assert!(filter.pass(A, &Message::Battery(80)));
+        assert!(filter.pass(B, &Message::Steps(100)));
+    }
+
+    #[test]
+    fn most_specific() {
+        let mut filter = Filter::default();
+        let battery = Message::Battery(80).id();
+
+        filter.unsubscribe(None, None);
+        filter.subscribe(Subscription { kind: Some(battery), device: None, interval: Duration::ZERO });
+        filter.subscribe(Subscription { kind: None, device: Some(B), interval: Duration::ZERO });
+
+        assert!(filter.pass(A, &Message::Battery(80)));
+        assert!(!filter.pass(A, &Message::Steps(100)));
+        assert!(filter.pass(B, &Message::Steps(100)));
+
+        filter.unsubscribe(Some(battery), None);
+        assert!(!filter.pass(A, &Message::Battery(80)));
+    }
+
+    #[test]
+    fn interval() {
+        let mut filter = Filter::default();
+        filter.subscribe(Subscription { kind: None, device: None, interval: Duration::from_secs(60) });
+
+        // Every device has its own pace
+        assert!(filter.pass(A, &Message::Battery(80)));
+        assert!(!filter.pass(A, &Message::Battery(79)));
+        assert!(filter.pass(B, &Message::Battery(80)));
+    }
+}
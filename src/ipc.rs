@@ -1,70 +1,275 @@
-use crate::{Error, devices::capabilities::battery::BatteryInfo, command::{self, Command}};
-use std::{path::Path, sync::Arc, pin::Pin, io::{self, Cursor}};
+use crate::{Error, devices::capabilities::{activity::ActivitySample, alarm::AlarmSlot, battery::{BatteryEvent, BatteryInfo, BatteryStatus}, firmware::{Part, Progress}, heartrate::HeartRateMeasurement}, command::{self, Command, Request}, filter::{Filter, Subscription}, protocol::{self, Protocol}};
+use crate::bio::Profile;
+use crate::devices::{DateTime, Info, WearLocation};
+use crate::store::{Aggregate, Sample};
+use std::{path::Path, sync::Arc, time::Duration};
 
 use bluer::Address;
-use futures::{Stream, StreamExt, lock::Mutex, stream::SelectAll};
+use chrono::{Datelike, Timelike};
+use futures::{Stream, StreamExt, lock::Mutex};
 use log::{debug, warn};
-use tokio::{net::{UnixListener, UnixStream}, sync::{watch, mpsc}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{UnixListener, UnixStream}, sync::{broadcast, mpsc, oneshot}, task::JoinHandle};
 
-use crate::devices::Version;
 
-type Messenger = Pin<Box<dyn Stream<Item = Message> + Send>>;
+// Messages a client may fall behind by before it starts losing them
+const QUEUE: usize = 1024;
+
+// Clients that say nothing by then are spoken to the old way
+const GREETING_WAIT: Duration = Duration::from_millis(200);
+
+// Longest a command may take, flashing firmware comes closest
+const REPLY_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Message and the device it concerns, zeroed address when it concerns none
+type Tagged = (Address, Message);
 
 pub struct Ipc {
     listener:     Arc<UnixListener>,
-    messengers:   Arc<Mutex<SelectAll<Messenger>>>,
-    tx:           watch::Sender<Message>,
-    rx:           watch::Receiver<Message>,
+    outbox:       Arc<Outbox>,
 
-    commander:    mpsc::Sender<Command>,
-    pub commands: Mutex<mpsc::Receiver<Command>>,
+    commander:    mpsc::Sender<Request>,
+    pub commands: Mutex<mpsc::Receiver<Request>>,
 }
 
-// enum ConnectionState {
-    // Connected,
-    // Disconnected,
-// }
+// Every client gets its own queue of what's sent, newcomers start with the latest state of each device
+struct Outbox {
+    tx:       broadcast::Sender<Tagged>,
+    snapshot: std::sync::Mutex<Vec<Tagged>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Discovering,
+    Connecting,
+    Authenticating,
+    Ready,
+    Lost,
+    Backoff(Duration), // until the next attempt
+}
 
-#[derive(Debug, Clone, Copy)]
+impl From<ConnectionState> for u8 {
+    fn from(state: ConnectionState) -> u8 {
+        match state {
+            ConnectionState::Discovering    => 0,
+            ConnectionState::Connecting     => 1,
+            ConnectionState::Authenticating => 2,
+            ConnectionState::Ready          => 3,
+            ConnectionState::Lost           => 4,
+            ConnectionState::Backoff(_)     => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Message {
     Battery(u8),
     Heartrate(u8),
     Steps(u32),
+    Activity(ActivitySample),
+    Sample(Sample),
+    Aggregate(Aggregate),
+    Alarm(AlarmSlot),
+    WearLocation(WearLocation),
+    DateTime((DateTime, i64)), // device clock, drift in seconds
+    Profile(Profile),
+    StepGoal((u32, u32)),    // goal, streak
+    GoalReached((u32, u32)), // goal, streak
+    FirmwarePart(Part),
+    FirmwareProgress(Progress),
+    Connection(ConnectionState),
+    Devices(Vec<(Address, ConnectionState)>),
+    Info(Info),
+    Name(String),
+    BatteryInfo(BatteryInfo),
+    BatteryEvent((BatteryEvent, u8)), // event, level
+    Overflow(u64), // messages the client missed
 }
 
 impl From<BatteryInfo> for Message {
     fn from(info: BatteryInfo) -> Message { Message::Battery(info.level) }
 }
 
+impl From<BatteryEvent> for u8 {
+    fn from(event: BatteryEvent) -> u8 {
+        match event {
+            BatteryEvent::Low      => 0,
+            BatteryEvent::Charging => 1,
+            BatteryEvent::Charged  => 2,
+        }
+    }
+}
+
+impl From<HeartRateMeasurement> for Message {
+    fn from(m: HeartRateMeasurement) -> Message { Message::Heartrate(m.bpm.try_into().unwrap_or(u8::MAX)) }
+}
+
 impl Message {
-    fn id(&self) -> u8 {
+    pub fn id(&self) -> u8 {
         match self {
-            Message::Battery(_)   => 11,
-            Message::Heartrate(_) => 12,
-            Message::Steps(_)     => 13,
+            Message::Battery(_)          => 11,
+            Message::Heartrate(_)        => 12,
+            Message::Steps(_)            => 13,
+            Message::Activity(_)         => 14,
+            Message::Sample(_)           => 15,
+            Message::Aggregate(_)        => 16,
+            Message::Alarm(_)            => 17,
+            Message::WearLocation(_)     => 18,
+            Message::DateTime(_)         => 19,
+            Message::Profile(_)          => 20,
+            Message::StepGoal(_)         => 21,
+            Message::GoalReached(_)      => 22,
+            Message::FirmwarePart(_)     => 23,
+            Message::FirmwareProgress(_) => 24,
+            Message::Connection(_)       => 25,
+            Message::Devices(_)          => 26,
+            Message::Info(_)             => 27,
+            Message::Name(_)             => 28,
+            Message::BatteryInfo(_)      => 29,
+            Message::BatteryEvent(_)     => 30,
+            Message::Overflow(_)         => 31,
         }
     }
 
-    pub fn to_le_bytes(self) -> Vec<u8> {
-        let mut vec = vec![b'M', b'S', b'G', self.id()];
+    // Latest one of the kind tells what a device is like now, rather than what happened
+    fn is_state(&self) -> bool {
+        matches!(self,
+            Message::Battery(_) | Message::BatteryInfo(_) | Message::Heartrate(_) | Message::Steps(_) |
+            Message::WearLocation(_) | Message::Profile(_) | Message::StepGoal(_) | Message::Connection(_) | Message::Info(_))
+    }
 
-        match self {
+    // Legacy clients don't hear which device it concerns
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        [b"MSG".as_slice(), &[self.id()], &self.body()].concat()
+    }
+
+    /// Message without the magic, as it's framed by the protocol.
+    pub fn encode(&self, device: Address) -> Vec<u8> {
+        [[self.id()].as_slice(), &device.0, &self.body()].concat()
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut vec = Vec::new();
+
+        match *self {
             Message::Battery(v) | Message::Heartrate(v) => {
                 vec.push(v);
             },
             Message::Steps(v) => {
                 vec.extend_from_slice(&v.to_le_bytes());
             }
+            Message::Activity(sample) => {
+                vec.extend_from_slice(&sample.timestamp.timestamp().to_le_bytes());
+                vec.extend_from_slice(&[sample.category, sample.intensity, sample.steps, sample.heartrate.unwrap_or(0)]);
+            }
+            Message::Sample(sample) => {
+                vec.push(sample.metric.into());
+                vec.extend_from_slice(&sample.timestamp.to_le_bytes());
+                vec.extend_from_slice(&sample.device.0);
+                vec.extend_from_slice(&sample.value.to_le_bytes());
+            }
+            Message::Aggregate(a) => {
+                vec.push(a.metric.into());
+                vec.extend_from_slice(&a.from.to_le_bytes());
+                vec.extend_from_slice(&a.to.to_le_bytes());
+                vec.extend_from_slice(&a.count.to_le_bytes());
+                vec.extend_from_slice(&a.min.to_le_bytes());
+                vec.extend_from_slice(&a.max.to_le_bytes());
+                vec.extend_from_slice(&a.sum.to_le_bytes());
+            }
+            Message::Alarm(a) => {
+                vec.extend_from_slice(&[a.slot, a.enabled as u8, a.time.hour() as u8, a.time.minute() as u8, a.smart as u8, a.repeat]);
+            }
+            Message::WearLocation(location) => {
+                vec.push(command::wearlocation_as_byte(location));
+            }
+            Message::DateTime((dt, drift)) => {
+                vec.extend_from_slice(&dt.timestamp().to_le_bytes());
+                vec.extend_from_slice(&drift.to_le_bytes());
+            }
+            Message::StepGoal((goal, streak)) | Message::GoalReached((goal, streak)) => {
+                vec.extend_from_slice(&goal.to_le_bytes());
+                vec.extend_from_slice(&streak.to_le_bytes());
+            }
+            Message::FirmwarePart(p) => {
+                vec.push(p.target.into());
+                vec.extend_from_slice(&*p.version);
+                vec.extend_from_slice(&(p.length as u32).to_le_bytes());
+                vec.extend_from_slice(&p.crc.to_le_bytes());
+                vec.push(p.outdated as u8);
+            }
+            Message::FirmwareProgress(p) => {
+                vec.push(p.target.into());
+                vec.extend_from_slice(&(p.sent as u32).to_le_bytes());
+                vec.extend_from_slice(&(p.total as u32).to_le_bytes());
+            }
+            Message::Connection(state) => {
+                let backoff = match state {
+                    ConnectionState::Backoff(d) => d.as_secs() as u32,
+                    _ => 0,
+                };
+
+                vec.push(state.into());
+                vec.extend_from_slice(&backoff.to_le_bytes());
+            }
+            Message::Devices(ref devices) => {
+                vec.push(devices.len().min(u8::MAX as usize) as u8);
+
+                for (address, state) in devices.iter().take(u8::MAX as usize) {
+                    vec.extend_from_slice(&address.0);
+                    vec.push((*state).into());
+                }
+            }
+            Message::Info(ref info) => {
+                // Unknown versions are zeroed
+                vec.push(info.hardware.unwrap_or(0));
+
+                for version in [info.profile, info.firmware, info.firmware_heart] {
+                    vec.extend_from_slice(&version.map(|v| *v).unwrap_or_default());
+                }
+
+                push_string(&mut vec, &info.name);
+            }
+            Message::Name(ref name) => {
+                push_string(&mut vec, name);
+            }
+            Message::BatteryInfo(info) => {
+                // Whatever the device doesn't report is zeroed
+                let status = match info.status {
+                    None                             => 0,
+                    Some(BatteryStatus::Low)         => 1,
+                    Some(BatteryStatus::Charging)    => 2,
+                    Some(BatteryStatus::NotCharging) => 3,
+                    Some(BatteryStatus::Full)        => 4,
+                };
+
+                vec.extend_from_slice(&[info.level, status]);
+                vec.extend_from_slice(&info.last_charged.map_or(0, |dt| dt.timestamp()).to_le_bytes());
+                vec.extend_from_slice(&info.cycles.unwrap_or(0).to_le_bytes());
+            }
+            Message::BatteryEvent((event, level)) => {
+                vec.extend_from_slice(&[event.into(), level]);
+            }
+            Message::Overflow(n) => {
+                vec.extend_from_slice(&n.to_le_bytes());
+            }
+            Message::Profile(ref p) => {
+                vec.push(p.bio.sex.into());
+                vec.extend_from_slice(&(p.bio.birth_date.year() as u16).to_le_bytes());
+                vec.extend_from_slice(&[p.bio.birth_date.month() as u8, p.bio.birth_date.day() as u8, p.bio.height, p.bio.weight]);
+                push_string(&mut vec, &p.alias);
+            }
         }
 
         vec
     }
 }
 
-struct Info {
-    name:     String,
-    address:  Address,
-    firmware: Version,
+// u8 length followed by as many bytes of UTF-8, cut short when it doesn't fit
+fn push_string(vec: &mut Vec<u8>, s: &str) {
+    let b = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+
+    vec.push(b.len() as u8);
+    vec.extend_from_slice(b);
 }
 
 impl Ipc {
@@ -73,31 +278,35 @@ impl Ipc {
         P: AsRef<Path>
     {
         let listener = Arc::new(UnixListener::bind(path)?);
-        let (tx, rx) = watch::channel(Message::Steps(0));
+        let outbox = Arc::new(Outbox { tx: broadcast::channel(QUEUE).0, snapshot: Default::default() });
         let (commander, commands) = mpsc::channel(8);
 
-        Ok(Self { listener, tx, rx, messengers: Arc::new(Mutex::new(SelectAll::new())), commander, commands: Mutex::new(commands) })
+        Ok(Self { listener, outbox, commander, commands: Mutex::new(commands) })
     }
 
-    pub fn add_messenger<M>(&self, messenger: M)
+    /// Transmits everything `messenger` yields on behalf of `device`, until it ends or the handle is aborted.
+    pub fn add_messenger<M>(&self, device: Address, messenger: M) -> JoinHandle<()>
         where
         M: Stream<Item = Message> + Send + 'static
     {
-        let mut messengers = self.messengers.try_lock().expect("locking messengers for modification");
+        let outbox = self.outbox.clone();
 
-        messengers.push(Box::pin(messenger));
-    }
+        tokio::spawn(async move {
+            let mut messenger = Box::pin(messenger);
 
-    pub async fn transmit(&self) -> Result<(), Error> {
-        let mut messengers = self.messengers.try_lock().expect("locking messsengers to pull and transmit");
+            while let Some(message) = messenger.next().await {
+                debug!("Transmitting message: {:?}", message.encode(device));
 
-        while let Some(message) = messengers.next().await {
-            debug!("Transmitting message: {:?}", message.to_le_bytes());
+                outbox.send(device, message);
+            }
+        })
+    }
 
-            self.tx.send(message).unwrap();
-        }
+    // Bypasses messengers, for news that must reach clients while the device is away
+    pub fn broadcast(&self, device: Address, message: Message) {
+        debug!("Broadcasting message: {:?}", message.encode(device));
 
-        Ok(())
+        self.outbox.send(device, message);
     }
 
     pub async fn listen(&self) -> Result<(), std::io::Error> {
@@ -106,11 +315,13 @@ impl Ipc {
                 Ok((stream, _)) => {
                     debug!("A new client has been connected");
 
-                    let rx = self.rx.clone();
+                    let outbox = self.outbox.clone();
                     let commander = self.commander.clone();
 
                     tokio::spawn(async move {
-                        Self::handle_client(stream, rx, commander).await.unwrap();
+                        if let Err(e) = Self::handle_client(stream, outbox, commander).await {
+                            warn!("Client has been disconnected: {e}");
+                        }
                     });
                 },
                 Err(e) => {
@@ -121,70 +332,172 @@ impl Ipc {
         }
     }
 
-    async fn handle_client(stream: UnixStream, mut message: watch::Receiver<Message>, commander: mpsc::Sender<Command>) -> Result<(), Error> {
-        let mut buf = [0; 32];
+    async fn handle_client(stream: UnixStream, outbox: Arc<Outbox>, commander: mpsc::Sender<Request>) -> Result<(), Error> {
+        let (mut reader, mut writer) = stream.into_split();
+
+        // Clients that never greet are spoken to the old way
+        let mut protocol = Protocol::Legacy;
+        let mut filter   = Filter::default();
+
+        // Nothing is sent until the client had a chance to pick the protocol, it's queued meanwhile
+        let (mut messages, snapshot) = outbox.subscribe();
+        let mut snapshot = Some(snapshot);
+
+        let wait = tokio::time::sleep(GREETING_WAIT);
+        tokio::pin!(wait);
+
+        let mut buf   = Vec::new();
+        let mut chunk = [0; 512];
+
+        let (replier, mut replies) = mpsc::channel::<Vec<u8>>(8);
 
         loop {
             tokio::select! {
-                Ok(_) = message.changed() => {
-                    stream.writable().await?;
+                () = &mut wait, if snapshot.is_some() => {
+                    let snapshot = snapshot.take().unwrap_or_default();
+                    writer.write_all(&events(protocol, &snapshot)).await?;
+                }
+                message = messages.recv(), if snapshot.is_none() => {
+                    let bytes = match message {
+                        Ok((device, message)) if filter.pass(device, &message) => {
+                            debug!("Sending message {:?}", message);
+                            protocol::event(protocol, device, &message)
+                        }
+                        Ok(_) => continue,
+                        // Whatever was missed is made up for by the state it left behind
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            warn!("Client fell behind by {n} messages");
+
+                            let mut snapshot = outbox.snapshot();
+                            snapshot.retain(|(device, message)| filter.pass(*device, message));
 
-                    Self::send(&stream, *message.borrow())?;
+                            let mut bytes = protocol::event(protocol, Address::any(), &Message::Overflow(n));
+                            bytes.extend(events(protocol, &snapshot));
+                            bytes
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    };
+
+                    writer.write_all(&bytes).await?;
                 }
-                Ok(_) = stream.readable() => {
-                    Self::read_and_command(&commander, &stream, &mut buf)?;
+                Some(reply) = replies.recv() => {
+                    writer.write_all(&reply).await?;
+                }
+                n = reader.read(&mut chunk) => {
+                    let n = n?;
+                    if n == 0 { return Ok(()) }
+
+                    debug!("Received {:?}", &chunk[..n]);
+                    buf.extend_from_slice(&chunk[..n]);
+
+                    let mut requests = Vec::new();
+
+                    let greeting = protocol::decode(&mut protocol, &mut buf, &mut requests)?;
+
+                    // Snapshot sent before the greeting is skipped by the client, so it's sent again
+                    if snapshot.is_some() || greeting.is_some() {
+                        let snapshot = snapshot.take().unwrap_or_else(|| outbox.snapshot());
+                        writer.write_all(&[greeting.unwrap_or_default(), events(protocol, &snapshot)].concat()).await?;
+                    }
+
+                    for (id, request) in requests {
+                        // Subscriptions concern just this client, they never reach the daemon
+                        let snapshot = match request {
+                            Ok(Request { device, command: Command::Subscribe((kind, interval)), .. }) => {
+                                let subscription = Subscription { kind, device, interval };
+                                filter.subscribe(subscription);
+
+                                // Catches up on what was subscribed to
+                                let mut snapshot = outbox.snapshot();
+                                snapshot.retain(|(device, message)| subscription.matches(*device, message) && filter.pass(*device, message));
+                                snapshot
+                            }
+                            Ok(Request { device, command: Command::Unsubscribe(kind), .. }) => {
+                                filter.unsubscribe(kind, device);
+                                Vec::new()
+                            }
+                            request => {
+                                Self::request(&commander, &replier, protocol, id, request);
+                                continue
+                            }
+                        };
+
+                        // Answer comes last, so the client knows it has caught up
+                        let response = id.map(|id| protocol::response(protocol, id, &[])).unwrap_or_default();
+                        writer.write_all(&[events(protocol, &snapshot), response].concat()).await?;
+                    }
                 }
-                else => return Ok(())
             }
         }
     }
 
-    fn read_and_command(commander: &mpsc::Sender<Command>, stream: &UnixStream, mut buf: &mut [u8]) -> Result<(), std::io::Error> {
-        match stream.try_read(buf) {
-            Ok(n) => {
-                if n == 0 { return Err(io::Error::new(io::ErrorKind::BrokenPipe, "read 0 bytes from stream, client has disconnected")) }
+    // Framed and JSON requests are answered once the command is done, no matter how it went
+    fn request(commander: &mpsc::Sender<Request>, replier: &mpsc::Sender<Vec<u8>>, protocol: Protocol, id: Option<u32>, request: Result<Request, Error>) {
+        let Some(id) = id else {
+            match request.map(|request| commander.try_send(request)) {
+                Ok(Ok(())) => {},
+                Ok(Err(_)) => warn!("Dropped a command, daemon is busy"),
+                Err(e)     => warn!("Couldn't parse incoming message: {e}"),
+            }
 
-                debug!("Recieved message {:?}", &buf);
+            return
+        };
 
-                use command::MAGIC;
+        let (tx, rx) = oneshot::channel();
 
-                while let Some(i) = buf.windows(MAGIC.len()).position(|window| window == MAGIC) {
+        let result = request.and_then(|mut request| {
+            request.reply = Some(tx);
+            commander.try_send(request).map_err(|_| Error::Busy)
+        });
 
-                    let mut r = Cursor::new(&buf[MAGIC.len() + i..]);
+        let replier = replier.clone();
 
-                    match Command::read(&mut r) {
-                        Ok(command) => {
-                            commander.try_send(command).unwrap()
-                        }
-                        Err(e) => warn!("Couldn't parse incoming message: {e}")
-                    }
+        tokio::spawn(async move {
+            let reply = match result {
+                Ok(()) => match tokio::time::timeout(REPLY_TIMEOUT, rx).await {
+                    Ok(reply) => reply.unwrap_or(Err(Error::NoDevice)),
+                    Err(_)    => Err(Error::Timeout),
+                },
+                Err(e) => Err(e),
+            };
 
-                    let len = (i as u64 + r.position()).min(buf.len() as u64);
-                    buf = &mut buf[(len as usize)..]
-                }
+            let bytes = match reply {
+                Ok(messages) => protocol::response(protocol, id, &messages),
+                Err(e)       => protocol::failure(protocol, id, &e),
+            };
 
-                Ok(())
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
-            Err(e) => Err(e)
-        }
+            let _ = replier.send(bytes).await;
+        });
     }
+}
 
-    fn send(stream: &UnixStream, message: Message) -> Result<(), std::io::Error> {
-        debug!("Sending message {:?}", message);
+impl Outbox {
+    fn send(&self, device: Address, message: Message) {
+        // Locked until sent, so nobody subscribing in between gets it twice or not at all
+        let mut snapshot = self.snapshot.lock().unwrap();
 
-        let buf = message.to_le_bytes();
+        if message.is_state() {
+            match snapshot.iter_mut().find(|(d, m)| *d == device && m.id() == message.id()) {
+                Some(entry) => entry.1 = message.clone(),
+                None        => snapshot.push((device, message.clone())),
+            }
+        }
 
-        match stream.try_write(&buf) {
-            Ok(n) => {
-                if n == 0 { return Err(io::Error::new(io::ErrorKind::BrokenPipe, "couldn't write a single byte to stream, client has disconnected")) }
+        // Nobody listening is fine
+        let _ = self.tx.send((device, message));
+    }
 
-                if n != buf.len() { warn!("Couldn't write all bytes in message, client might be confused. This incident will be ignored...") }
+    fn subscribe(&self) -> (broadcast::Receiver<Tagged>, Vec<Tagged>) {
+        let snapshot = self.snapshot.lock().unwrap();
 
-                Ok(())
-            },
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
-            Err(e) => Err(e)
-        }
+        (self.tx.subscribe(), snapshot.clone())
+    }
+
+    fn snapshot(&self) -> Vec<Tagged> {
+        self.snapshot.lock().unwrap().clone()
     }
 }
+
+fn events(protocol: Protocol, messages: &[Tagged]) -> Vec<u8> {
+    messages.iter().flat_map(|(device, message)| protocol::event(protocol, *device, message)).collect()
+}
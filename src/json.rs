@@ -0,0 +1,324 @@
+use std::{path::PathBuf, time::Duration};
+
+use bluer::Address;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, de};
+use serde_json::{Value, json};
+
+use crate::{Error, bio::{Bio, Profile}, command::{Command, CommandAction, Range, Request}, devices::{DateTime, WearLocation}, ipc::{ConnectionState, Message}, store::Metric};
+use crate::devices::capabilities::{alarm::AlarmSlot, alert::{AlertLevel, Pattern}};
+
+// Names of message types, as clients see them in `message`
+const MESSAGES: [(u8, &str); 21] = [
+    (11, "battery"),
+    (12, "heartrate"),
+    (13, "steps"),
+    (14, "activity"),
+    (15, "sample"),
+    (16, "aggregate"),
+    (17, "alarm"),
+    (18, "wear_location"),
+    (19, "date_time"),
+    (20, "profile"),
+    (21, "step_goal"),
+    (22, "goal_reached"),
+    (23, "firmware_part"),
+    (24, "firmware_progress"),
+    (25, "connection"),
+    (26, "devices"),
+    (27, "info"),
+    (28, "name"),
+    (29, "battery_info"),
+    (30, "battery_event"),
+    (31, "overflow"),
+];
+
+// Mirror of `Command`, what's set is left out to get it instead.
+// Unit variants would take any field, those without fields are empty structs instead.
+// Besides the command and its fields, a line may hold:
+//
+// id:      u32, echoed in the answer, zero when left out
+// device:  "AA:BB:CC:DD:EE:FF", first device when left out
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+enum Call {
+    Activity {},
+    Aggregate { metric: Metric, #[serde(default)] from: i64, #[serde(default = "forever")] to: i64, bucket: u32 },
+    Alarm { set: Option<AlarmSlot> },
+    AlarmEnable { slot: u8, enabled: bool },
+    Alert { #[serde(default)] level: AlertLevel },
+    AlertPattern { #[serde(default)] pattern: Pattern },
+    Battery {},
+    DateTime { #[serde(default, deserialize_with = "datetime_from_str")] set: Option<DateTime> },
+    Devices {},
+    Firmware { path: PathBuf, #[serde(default)] dry_run: bool },
+    Heartrate {},
+    HeartrateContinuous { enable: bool },
+    Info {},
+    Name {},
+    Profile { set: Option<ProfileSet> },
+    Query { metric: Metric, #[serde(default)] from: i64, #[serde(default = "forever")] to: i64 },
+    StepGoal { set: Option<u32> },
+    Steps { set: Option<u32> },
+    Subscribe { #[serde(default, deserialize_with = "kind_from_str")] message: Option<u8>, #[serde(default)] interval: u64 }, // ms
+    Unsubscribe { #[serde(default, deserialize_with = "kind_from_str")] message: Option<u8> },
+    WearLocation { set: Option<WearLocation> },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSet {
+    alias: String,
+    bio:   Bio,
+}
+
+fn forever() -> i64 { i64::MAX }
+
+/// Request on a single line, along with its id so that even a broken one can be answered.
+pub fn request(line: &[u8]) -> (u32, Result<Request, Error>) {
+    let mut value: Value = match serde_json::from_slice(line) {
+        Ok(value) => value,
+        Err(e)    => return (0, Err(e.into())),
+    };
+
+    // Taken out, so that the command is left with nothing but its own fields
+    let mut take = |key| value.as_object_mut().and_then(|object| object.remove(key)).unwrap_or_default();
+
+    let id     = take("id").as_u64().unwrap_or(0) as u32;
+    let device = take("device");
+
+    let request = Option::<Address>::deserialize(device)
+        .and_then(|device| Call::deserialize(value).map(|call| (device, call)))
+        .map(|(device, call)| {
+            let mut command = Command::from(call);
+
+            // Device narrows history down, rather than being asked
+            if let Command::Query(range) | Command::Aggregate((range, _)) = &mut command {
+                range.device = device;
+            }
+
+            Request { device, command, reply: None }
+        });
+
+    (id, request.map_err(Into::into))
+}
+
+impl From<Call> for Command {
+    fn from(call: Call) -> Command {
+        // Getting is asking for nothing to be set
+        fn action<T>(set: &Option<T>) -> CommandAction {
+            match set {
+                Some(_) => CommandAction::Set,
+                None    => CommandAction::Get,
+            }
+        }
+
+        match call {
+            Call::Activity {}                            => Command::Activity,
+            Call::Aggregate { metric, from, to, bucket } => Command::Aggregate((Range { metric, device: None, from, to }, bucket)),
+            Call::Alarm { set }                          => Command::Alarm((action(&set), set)),
+            Call::AlarmEnable { slot, enabled }          => Command::AlarmEnable((slot, enabled)),
+            Call::Alert { level }                        => Command::Alert(level),
+            Call::AlertPattern { pattern }               => Command::AlertPattern(pattern),
+            Call::Battery {}                             => Command::Battery,
+            Call::DateTime { set }                       => Command::DateTime((action(&set), set)),
+            Call::Devices {}                             => Command::Devices,
+            Call::Firmware { path, dry_run }             => Command::Firmware((path, dry_run)),
+            Call::Heartrate {}                           => Command::Heartrate,
+            Call::HeartrateContinuous { enable }         => Command::HeartrateContinuous(enable),
+            Call::Info {}                                => Command::Info,
+            Call::Name {}                                => Command::Name,
+            Call::Profile { set }                        => {
+                let set = set.map(|ProfileSet { alias, bio }| Profile { alias, bio });
+                Command::Profile((action(&set), set))
+            }
+            Call::Query { metric, from, to }             => Command::Query(Range { metric, device: None, from, to }),
+            Call::StepGoal { set }                       => Command::StepGoal((action(&set), set)),
+            Call::Steps { set }                          => Command::Steps((action(&set), set)),
+            Call::Subscribe { message, interval }        => Command::Subscribe((message, Duration::from_millis(interval))),
+            Call::Unsubscribe { message }                => Command::Unsubscribe(message),
+            Call::WearLocation { set }                   => Command::WearLocation((action(&set), set)),
+        }
+    }
+}
+
+/// Message as an object named by `message`, with the device it concerns, null when it concerns none.
+pub fn message(device: Address, message: &Message) -> Value {
+    let name = MESSAGES.iter().find(|(id, _)| *id == message.id()).map_or("unknown", |(_, name)| name);
+
+    let mut value = json!({
+        "message": name,
+        "device":  (device != Address::any()).then_some(device),
+    });
+
+    let fields = match message {
+        Message::Battery(level) => json!({ "level": level }),
+        Message::Heartrate(bpm) => json!({ "bpm": bpm }),
+        Message::Steps(steps) => json!({ "steps": steps }),
+        Message::Activity(sample) => json!({
+            "timestamp": sample.timestamp.timestamp(),
+            "category":  sample.category,
+            "intensity": sample.intensity,
+            "steps":     sample.steps,
+            "heartrate": sample.heartrate,
+        }),
+        Message::Sample(sample) => json!({ "metric": sample.metric, "timestamp": sample.timestamp, "value": sample.value }),
+        Message::Aggregate(a) => json!({
+            "metric": a.metric,
+            "from":   a.from,
+            "to":     a.to,
+            "count":  a.count,
+            "min":    a.min,
+            "max":    a.max,
+            "sum":    a.sum,
+        }),
+        Message::Alarm(slot) => json!(slot),
+        Message::WearLocation(l) => json!({ "location": l }),
+        Message::DateTime((dt, drift)) => json!({ "time": datetime_to_str(dt), "drift": drift }),
+        Message::Profile(p) => json!({ "alias": p.alias, "bio": p.bio }),
+        Message::StepGoal((goal, streak)) | Message::GoalReached((goal, streak)) => json!({ "goal": goal, "streak": streak }),
+        Message::FirmwarePart(p) => json!({
+            "target":   p.target,
+            "version":  p.version.to_string(),
+            "length":   p.length,
+            "crc":      p.crc,
+            "outdated": p.outdated,
+        }),
+        Message::FirmwareProgress(p) => json!({ "target": p.target, "sent": p.sent, "total": p.total }),
+        Message::Connection(state) => connection(*state),
+        Message::Devices(devices) => {
+            let devices: Vec<Value> = devices.iter()
+                .map(|(address, state)| {
+                    let mut device = connection(*state);
+                    device["device"] = json!(address);
+                    device
+                })
+                .collect();
+
+            json!({ "devices": devices })
+        }
+        Message::Info(info) => json!({
+            "name":           info.name,
+            "hardware":       info.hardware,
+            "profile":        info.profile.map(|v| v.to_string()),
+            "firmware":       info.firmware.map(|v| v.to_string()),
+            "firmware_heart": info.firmware_heart.map(|v| v.to_string()),
+        }),
+        Message::Name(name) => json!({ "name": name }),
+        Message::BatteryInfo(info) => json!({
+            "level":        info.level,
+            "status":       info.status,
+            "last_charged": info.last_charged.as_ref().map(datetime_to_str),
+            "cycles":       info.cycles,
+        }),
+        Message::BatteryEvent((event, level)) => json!({ "event": event, "level": level }),
+        Message::Overflow(n) => json!({ "dropped": n }),
+    };
+
+    if let (Value::Object(value), Value::Object(fields)) = (&mut value, fields) {
+        value.extend(fields);
+    }
+
+    value
+}
+
+pub fn response(id: u32, messages: &[(Address, Message)]) -> Value {
+    let messages: Vec<Value> = messages.iter().map(|(device, m)| message(*device, m)).collect();
+
+    json!({ "id": id, "messages": messages })
+}
+
+pub fn failure(id: u32, error: &Error) -> Value {
+    json!({ "id": id, "code": error.code(), "error": error.to_string() })
+}
+
+// state:   name,
+// backoff: seconds until the next attempt, only while backing off
+fn connection(state: ConnectionState) -> Value {
+    let name = match state {
+        ConnectionState::Discovering    => "discovering",
+        ConnectionState::Connecting     => "connecting",
+        ConnectionState::Authenticating => "authenticating",
+        ConnectionState::Ready          => "ready",
+        ConnectionState::Lost           => "lost",
+        ConnectionState::Backoff(_)     => "backoff",
+    };
+
+    match state {
+        ConnectionState::Backoff(d) => json!({ "state": name, "backoff": d.as_secs() }),
+        _                           => json!({ "state": name }),
+    }
+}
+
+fn datetime_to_str(dt: &DateTime) -> String {
+    dt.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// RFC 3339, e.g. "2024-03-01T08:30:00+01:00"
+fn datetime_from_str<'de, D>(d: D) -> Result<Option<DateTime>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let s = String::deserialize(d)?;
+
+    chrono::DateTime::parse_from_rfc3339(&s)
+        .map(|dt| Some(dt.with_timezone(&Utc).into()))
+        .map_err(de::Error::custom)
+}
+
+// Message type by name, "all" or null for every one of them
+fn kind_from_str<'de, D>(d: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    match Option::<String>::deserialize(d)?.as_deref() {
+        None | Some("all") => Ok(None),
+        Some(s) => MESSAGES.iter()
+            .find(|(_, name)| *name == s)
+            .map(|(id, _)| Some(*id))
+            .ok_or_else(|| de::Error::custom(format!("unknown message type {s}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let (id, result) = request(br#"{"id": 5, "command": "steps", "set": 1000, "device": "AA:BB:CC:DD:EE:FF"}"#);
+        let steps = result.unwrap();
+
+        assert_eq!(id, 5);
+        assert_eq!(steps.device, Some(Address::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF])));
+        assert!(matches!(steps.command, Command::Steps((CommandAction::Set, Some(1000)))));
+
+        let (id, result) = request(br#"{"command": "aggregate", "metric": "steps", "bucket": 60, "device": "AA:BB:CC:DD:EE:FF"}"#);
+        assert_eq!(id, 0);
+        assert!(matches!(result.unwrap().command, Command::Aggregate((Range { device: Some(_), from: 0, .. }, 60))));
+
+        let (_, result) = request(br#"{"command": "date_time"}"#);
+        assert!(matches!(result.unwrap().command, Command::DateTime((CommandAction::Get, None))));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        for line in [
+            br#"{"id": 7, "command": "steps", "sett": 5}"#.as_slice(),
+            br#"{"id": 7, "command": "battery", "extra": 1}"#,
+            br#"{"id": 7, "command": "profile", "set": {"alias": "a", "bio": {"age": 3}}}"#,
+        ] {
+            let (id, result) = request(line);
+
+            assert_eq!(id, 7);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(request(b"{\"command\": "), (0, Err(_))));
+        assert!(matches!(request(br#"{"id": 2, "command": "battery", "device": "nowhere"}"#), (2, Err(_))));
+        assert!(matches!(request(br#"{"id": 2, "command": "fly"}"#), (2, Err(_))));
+    }
+}
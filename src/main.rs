@@ -5,216 +5,870 @@ mod bio;
 mod appraiser;
 mod error;
 mod command;
+mod config;
 mod ipc;
+mod state;
+mod store;
+mod notifications;
+mod registry;
+mod protocol;
+mod filter;
+mod json;
+mod client;
 
 use std::time::Duration;
-use std::{sync::Arc, ops::DerefMut, path::Path, fs};
+use std::{sync::{Arc, atomic::{AtomicU32, Ordering}}, path::PathBuf, fs};
 
 use std::os::unix::fs::FileTypeExt;
 
-use clap::Parser;
-use command::{Command, CommandAction};
-use devices::miband;
-use ipc::{Ipc, Message};
-use log::{debug, warn};
-use tokio::sync::mpsc::Receiver;
+use clap::{Parser, Subcommand};
+use command::{Command, CommandAction, Reply, Request};
+use bio::{Profile, User};
+use config::Config;
+use ipc::{ConnectionState, Ipc, Message};
+use log::{debug, warn, LevelFilter};
+use notifications::Signal;
+use state::State;
+use store::Store;
+use registry::Registry;
+use tokio::sync::{broadcast, oneshot, mpsc::{Receiver, error::TrySendError}};
+use tokio::task::JoinHandle;
 use self::error::Error;
 use bluer::{AdapterEvent, Address, Adapter};
-use futures::{stream::StreamExt, channel::mpsc};
+use chrono::{Local, Utc};
+use futures::{future::{self, BoxFuture}, stream, SinkExt, Stream, stream::StreamExt, channel::mpsc, lock::Mutex};
+use tokio::time::Instant;
 
+use crate::devices::DateTime;
 use crate::devices::bluetooth::BluetoothDevice;
+use crate::devices::miband::{MiBand, OneS};
+use crate::devices::simulator::Simulator;
+use crate::devices::capabilities::alert::Pattern;
+use crate::devices::capabilities::clock::Clock;
+use crate::devices::capabilities::battery::{BatteryEvent, BatteryInfo};
+
+// Locally administered, won't collide with a real band
+const SIMULATOR_ADDRESS: Address = Address([0x8A, 0x0F, 0x10, 0x00, 0x00, 0x01]);
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long)]
-    quiet: bool,
+    /// Path to the configuration file
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Path to the IPC socket
+    #[arg(short, long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Device to ask, the first ready one by default
+    #[arg(short, long, global = true)]
+    device: Option<Address>,
+
+    /// Print JSON lines instead of text
+    #[arg(long, global = true)]
+    json: bool,
 
-    #[arg(short, long)]
-    address: Option<String>,
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Keep devices connected and serve clients
+    Daemon {
+        /// Only log errors
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Address of a device to connect to, can be repeated
+        #[arg(short, long)]
+        address: Vec<Address>,
+
+        /// Name of the bluetooth adapter to use (e.g. hci0)
+        #[arg(long)]
+        adapter: Option<String>,
+
+        /// Talk to a simulated MiBand 1S instead of a real device
+        #[arg(long)]
+        simulate: bool,
+    },
+    #[command(flatten)]
+    Client(client::Action),
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
 
-    let _args = Args::parse();
+    let args = Args::parse();
+
+    let mut config = Config::load(args.config.as_deref())?;
+
+    if let Some(s) = args.socket { config.socket = s }
 
-    enable_logging();
+    match args.mode {
+        Mode::Daemon { quiet, address, adapter, simulate } => {
+            if quiet                 { config.log_level = LevelFilter::Error }
+            if !address.is_empty()   { config.addresses = address }
+            if let Some(a) = adapter { config.adapter   = Some(a) }
+            if simulate              { config.simulate  = true }
 
-    let socket = Path::new("socket");
+            daemon(config).await
+        }
+        Mode::Client(action) => client::run(&config.socket, args.device, args.json, action).await,
+    }
+}
+
+async fn daemon(config: Config) -> Result<(), Error> {
+    enable_logging(config.log_level);
+
+    let socket = config.socket.as_path();
     if socket.try_exists()? {
         let meta = socket.metadata()?;
         if meta.file_type().is_socket() {
             fs::remove_file(socket)?;
         }
+    } else if let Some(parent) = socket.parent() {
+        fs::create_dir_all(parent)?;
     }
 
-    let _address = miband::ADDRESS;
-
-    keep_alive("socket", None).await?;
+    keep_alive(config).await?;
 
     Ok(())
 }
 
-fn enable_logging() {
+fn enable_logging(level: LevelFilter) {
     let mut log_builder = env_logger::Builder::new();
 
-    #[cfg(not(debug_assertions))] {
-        log_builder.filter_level(log::LevelFilter::Warn);
-    }
-
-    #[cfg(debug_assertions)] {
-        log_builder.filter_level(log::LevelFilter::Debug);
-    }
+    log_builder.filter_level(level);
 
     log_builder.init();
 }
 
-async fn keep_alive<P>(socket: P, address: Option<Address>) -> Result<(), Error>
-where
-    P: AsRef<Path>
-{
-    let ipc = Arc::new(Ipc::new(socket)?);
+// Everything device tasks have in common
+struct Daemon {
+    config:   Config,
+    ipc:      Ipc,
+    state:    Mutex<State>,
+    store:    Arc<Store>,
+    registry: Registry,
+    alerts:   broadcast::Sender<Signal>,
+}
+
+// Last battery report of a device, outlives its connections
+type LastBattery = Arc<std::sync::Mutex<Option<BatteryInfo>>>;
+
+async fn keep_alive(config: Config) -> Result<(), Error> {
+    let ipc   = Ipc::new(&config.socket)?;
+    let state = State::load(&config.state)?;
+    let store = Arc::new(Store::open(&config.store)?);
+
+    // Device tasks subscribe on their own, signals are dropped while there are none
+    let (alerts, _) = broadcast::channel::<Signal>(4);
+    if let Some(n) = &config.notifications {
+        notifications::listen(n, alerts.clone()).await?;
+    }
+
+    let daemon = Arc::new(Daemon { config, ipc, state: Mutex::new(state), store, registry: Registry::default(), alerts });
 
     {
         debug!("Listening for IPC clients...");
-        let ipc = ipc.clone();
-        tokio::spawn(async move { ipc.listen().await });
+        let daemon = daemon.clone();
+        tokio::spawn(async move { daemon.ipc.listen().await });
     }
 
-    let (mut tx, rx) = mpsc::channel::<Message>(1);
-    ipc.add_messenger(rx);
+    match daemon.config.simulate {
+        true  => {
+            let addresses = match daemon.config.addresses.is_empty() {
+                true  => vec![SIMULATOR_ADDRESS],
+                false => daemon.config.addresses.clone(),
+            };
+
+            for address in addresses {
+                let user = user(&daemon.config, &mut *daemon.state.lock().await, address)?;
+                manage(&daemon, MiBand::<OneS>::boxed(Box::new(Simulator::new(address)), user));
+            }
+        }
+        false => {
+            tokio::spawn(discovery(daemon.clone()));
+        }
+    }
 
-    let bt_session = bluer::Session::new().await?;
-    let bt_adapter = bt_session.default_adapter().await?;
+    let mut requests = daemon.ipc.commands.lock().await;
 
-    let mut device = discover(&bt_adapter, address).await?;
+    // Commands that concern a single device are handed over to its task, the rest are answered here
+    while let Some(request) = requests.recv().await {
+        let store = &daemon.store;
+
+        let reply = match request.command {
+            Command::Devices => {
+                Ok(vec![(Address::any(), Message::Devices(daemon.registry.list()))])
+            }
+            Command::Query(ref range) => {
+                store.range(range.metric, range.device, range.from, range.to)
+                    .map(|samples| samples.into_iter().map(|s| (s.device, Message::Sample(s))).collect())
+            }
+            Command::Aggregate((ref range, bucket)) => {
+                let device = range.device.unwrap_or(Address::any());
+
+                store.aggregate(range.metric, range.device, range.from, range.to, bucket)
+                    .map(|aggregates| aggregates.into_iter().map(|a| (device, Message::Aggregate(a))).collect())
+            }
+            _ => {
+                let commands = match daemon.registry.commands(request.device) {
+                    Ok(commands) => commands,
+                    Err(e)       => {
+                        respond(&daemon.ipc, request.reply, Err(e));
+                        continue
+                    }
+                };
+
+                match commands.try_send(request) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(request))   => respond(&daemon.ipc, request.reply, Err(Error::Busy)),
+                    Err(TrySendError::Closed(request)) => respond(&daemon.ipc, request.reply, Err(Error::NoDevice)),
+                }
+
+                continue
+            }
+        };
+
+        respond(&daemon.ipc, request.reply, reply);
+    }
+
+    Ok(())
+}
+
+// Legacy clients can't be answered directly, everyone hears the reply instead
+fn respond(ipc: &Ipc, reply: Option<oneshot::Sender<Reply>>, result: Reply) {
+    match reply {
+        Some(reply) => { let _ = reply.send(result); }
+        None => match result {
+            Ok(messages) => for (device, message) in messages {
+                ipc.broadcast(device, message);
+            },
+            Err(e) => warn!("Couldn't handle command: {e}"),
+        }
+    }
+}
+
+// Hands the device over to a task of its own, unless it's managed already
+fn manage(daemon: &Arc<Daemon>, device: Box<dyn BluetoothDevice>) {
+    let (commander, commands) = tokio::sync::mpsc::channel(8);
+
+    if daemon.registry.insert(device.address(), commander) {
+        tokio::spawn(connection(daemon.clone(), device, commands));
+    }
+}
+
+// Keeps a single device connected for as long as the daemon runs
+async fn connection(daemon: Arc<Daemon>, mut device: Box<dyn BluetoothDevice>, mut commands: Receiver<Request>) {
+    let address = device.address();
+    let config  = &daemon.config;
+
+    let (mut tx, rx) = mpsc::channel::<Message>(1);
+    daemon.ipc.add_messenger(address, record(&daemon.store, address, rx));
+
+    let goal = Arc::new(AtomicU32::new(0));
+    let battery = LastBattery::default();
+    let mut alerts = daemon.alerts.subscribe();
+    let mut backoff = Backoff::new();
+    let mut messengers = Vec::new();
 
     let mut interval = tokio::time::interval(Duration::from_secs(1));
 
+    let resync = Duration::from_secs(config.clock_sync.max(1));
+    let mut resync = tokio::time::interval_at(Instant::now() + resync, resync);
+
     loop {
-        debug!("Connecting to {}", &device.address());
-        device.connect().await?;
+        if let Err(e) = establish(&daemon, device.as_mut(), &goal, &battery, &mut messengers).await {
+            warn!("Couldn't connect to {address}: {e}");
 
-        capabilities(&ipc.clone(), device.as_ref()).await?;
+            messengers.drain(..).for_each(|m| m.abort());
+            backoff.wait(&daemon, address).await;
+            continue
+        }
 
-        let transmitter = {
-            let ipc = ipc.clone();
+        backoff.reset();
+        announce(&daemon, address, ConnectionState::Ready);
 
-            tokio::spawn(async move { ipc.transmit().await.unwrap(); })
-        };
+        match device.info().await {
+            Ok(info) => tx.send(Message::Info(info)).await.unwrap(),
+            Err(e)   => warn!("Couldn't read device information: {e}"),
+        }
+
+        if let Some(activity) = device.activity() {
+            let mut samples = Vec::new();
+            let result = activity.sync_activity(&mut |block| samples.extend(block)).await;
+
+            for sample in samples {
+                tx.send(Message::Activity(sample)).await.unwrap();
+            }
+
+            if let Err(e) = result {
+                warn!("Couldn't sync activity: {e}");
+            }
+        }
 
-        let mut commands = ipc.commands.lock().await;
+        // Patterns take a while to play, commands and the connection aren't kept waiting meanwhile
+        let mut playback: Option<BoxFuture<'_, Result<(), Error>>> = None;
 
         loop {
             tokio::select! {
-                Ok(_) = command(device.as_ref(), &mut commands, &mut tx) => {}
+                Some(request) = commands.recv() => {
+                    let reply = match request.command {
+                        // Answered once it starts playing
+                        Command::AlertPattern(pattern) => replay(&mut playback, device.as_ref(), pattern).await.map(|_| Vec::new()),
+                        c => command(&daemon, device.as_ref(), &goal, c, &mut tx).await,
+                    };
+
+                    let reply = reply
+                        .map(|messages| messages.into_iter().inspect(|m| daemon.store.record(address, m)).map(|m| (address, m)).collect());
+
+                    respond(&daemon.ipc, request.reply, reply);
+                }
+                result = async { playback.as_mut().unwrap().await }, if playback.is_some() => {
+                    playback = None;
+
+                    if let Err(e) = result {
+                        warn!("Couldn't play alert pattern: {e}");
+                    }
+                }
                 _     = interval.tick() => {
-                    if !device.is_connected().await.unwrap() {
-                        debug!("Lost connection to {}", device.address());
+                    if !device.is_connected().await.unwrap_or(false) {
+                        debug!("Lost connection to {address}");
                         break
                     }
                 }
-                else => {
-                    transmitter.abort();
-                    break
+                Ok(signal) = alerts.recv() => {
+                    let result = match signal {
+                        Signal::Level(level) => match device.alert() {
+                            Some(alert) => alert.alert(level).await,
+                            None        => Ok(()),
+                        },
+                        Signal::Pattern(pattern) => match device.alert() {
+                            Some(_) => replay(&mut playback, device.as_ref(), pattern).await,
+                            None    => Ok(()),
+                        },
+                    };
+
+                    if let Err(e) = result {
+                        warn!("Couldn't alert: {e}");
+                    }
+                }
+                _     = resync.tick(), if config.clock_sync > 0 => {
+                    if let Some(clock) = device.clock() {
+                        if let Err(e) = synchronize(clock).await {
+                            warn!("Couldn't synchronize clock: {e}");
+                        }
+                    }
                 }
+                else => break
             }
         }
-    } 
+
+        // Streams of a lost connection might never end on their own
+        messengers.drain(..).for_each(|m| m.abort());
+
+        announce(&daemon, address, ConnectionState::Lost);
+
+        // Commands that came in just before are left to nobody otherwise
+        while let Ok(request) = commands.try_recv() {
+            respond(&daemon.ipc, request.reply, Err(Error::NotConnected));
+        }
+    }
+}
+
+// Brings a known device from nothing to usable, any failure is worth another attempt
+async fn establish(daemon: &Daemon, device: &mut dyn BluetoothDevice, goal: &Arc<AtomicU32>, battery: &LastBattery, messengers: &mut Vec<JoinHandle<()>>) -> Result<(), Error> {
+    let address = device.address();
+
+    announce(daemon, address, ConnectionState::Connecting);
+    debug!("Connecting to {address}");
+    device.connect().await?;
+
+    announce(daemon, address, ConnectionState::Authenticating);
+    device.authenticate().await?;
+
+    restore(daemon, device, goal).await?;
+
+    capabilities(daemon, device, goal, battery, messengers).await
+}
+
+fn announce(daemon: &Daemon, address: Address, state: ConnectionState) {
+    daemon.registry.set_state(address, state);
+    daemon.ipc.broadcast(address, Message::Connection(state));
+}
+
+// Doubles the wait after every failed attempt, so an absent band isn't hammered
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { delay: BACKOFF_MIN }
+    }
+
+    fn reset(&mut self) {
+        self.delay = BACKOFF_MIN;
+    }
+
+    async fn wait(&mut self, daemon: &Daemon, address: Address) {
+        debug!("Retrying in {}s", self.delay.as_secs());
+        announce(daemon, address, ConnectionState::Backoff(self.delay));
+
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(BACKOFF_MAX);
+    }
+}
+
+// Looks for supported devices until every configured one is found, or forever when none are
+async fn discovery(daemon: Arc<Daemon>) {
+    let mut backoff = Backoff::new();
+
+    loop {
+        announce(&daemon, Address::any(), ConnectionState::Discovering);
+
+        match discover(&daemon).await {
+            Ok(()) if found_all(&daemon) => return,
+            Ok(()) => debug!("Discovery has ended prematurely"),
+            Err(e) => warn!("Couldn't discover devices: {e}"),
+        }
+
+        backoff.wait(&daemon, Address::any()).await;
+    }
+}
+
+fn found_all(daemon: &Daemon) -> bool {
+    let addresses = &daemon.config.addresses;
+
+    !addresses.is_empty() && addresses.iter().all(|a| daemon.registry.contains(*a))
+}
+
+async fn adapter(config: &Config) -> Result<Adapter, Error> {
+    let session = bluer::Session::new().await?;
+
+    // Adapter keeps the session alive on its own
+    match &config.adapter {
+        Some(name) => Ok(session.adapter(name)?),
+        None       => Ok(session.default_adapter().await?),
+    }
 }
 
-async fn discover(adapter: &Adapter, address: Option<Address>) -> Result<Box<dyn BluetoothDevice>, bluer::Error> {
+async fn discover(daemon: &Arc<Daemon>) -> Result<(), Error> {
+    let config  = &daemon.config;
+    let adapter = adapter(config).await?;
+
     adapter.set_powered(true).await?;
 
     let mut discover = adapter.discover_devices().await?;
 
     debug!("Discovering devices...");
 
-    loop {
-        if let Some(AdapterEvent::DeviceAdded(discovered)) = discover.next().await {
-            debug!("Discovered {}", discovered);
+    while let Some(event) = discover.next().await {
+        let AdapterEvent::DeviceAdded(discovered) = event else {
+            continue
+        };
+
+        debug!("Discovered {}", discovered);
+
+        if !config.addresses.is_empty() && !config.addresses.contains(&discovered) {
+            continue;
+        }
+
+        if daemon.registry.contains(discovered) {
+            continue;
+        }
 
-            if address.is_some() && Some(discovered) != address {
-                continue;
+        let Ok(device) = adapter.device(discovered) else {
+            continue
+        };
+
+        debug!("Appraising... {}", device.address());
+
+        match appraiser::appraise(&device, config).await {
+            Ok(Some(construct)) => {
+                let user = user(config, &mut *daemon.state.lock().await, discovered)?;
+                manage(daemon, construct(Box::new(device), user));
+
+                // Radio is better spent on connections once everyone is here
+                if found_all(daemon) {
+                    return Ok(())
+                }
             }
+            Ok(None) => debug!("{} is not a supported device", discovered),
+            Err(e)   => warn!("Couldn't appraise {}: {}", discovered, e),
+        }
+    }
 
-            let Ok(device) = adapter.device(discovered) else {
-                continue
-            };
+    Ok(())
+}
+
+fn user(config: &Config, state: &mut State, address: Address) -> Result<User, Error> {
+    let identity = state.identity(address, config.alias.as_deref())?;
+    let bio = state.device(address).bio.clone().unwrap_or_else(|| config.bio.clone());
+
+    Ok(User { id: identity.id, alias: identity.alias, bio, clock: config.clock })
+}
+
+// Device forgets settings that only the daemon can remember
+async fn restore(daemon: &Daemon, device: &dyn BluetoothDevice, goal: &AtomicU32) -> Result<(), Error> {
+    let Daemon { config, state, .. } = daemon;
+    let address = device.address();
+
+    // Writes can take long, the band may wait for a tap, other devices shouldn't wait along
+    let (user, alarms, step_goal, wear_location) = {
+        let mut state = state.lock().await;
+        let user   = user(config, &mut state, address)?;
+        let alarms = state.alarms(address, &config.alarms);
+        let device = state.device(address);
 
-            debug!("Appraising... {}", device.address());
+        (user, alarms, device.goal.or(config.step_goal), device.wear.or(config.wear))
+    };
 
-            if let Some(bt) = appraiser::appraise(device) {
-                return Ok(bt)
+    // Connection authenticates with the profile known at discovery, it might have changed since
+    if let Some(profile) = device.profile() {
+        profile.set_user(&user).await?;
+    }
+
+    if let Some(clock) = device.clock() {
+        clock.set_datetime(&Utc::now().into()).await?;
+    }
+
+    if let Some(alarm) = device.alarm() {
+        for slot in alarms {
+            skip_unsupported("alarm", alarm.set_alarm(&slot).await)?;
+        }
+    }
+
+    if let Some(steps) = device.steps() {
+        if let Some(n) = step_goal {
+            if skip_unsupported("step goal", steps.set_goal(n).await)? {
+                goal.store(n, Ordering::Relaxed);
             }
         }
     }
+
+    if let Some(wear) = device.wear() {
+        if let Some(location) = wear_location {
+            skip_unsupported("wear location", wear.set_wear_location(location).await)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Setting the device turns down is left out, it would be turned down on every attempt to connect.
+// Whether it was set is returned.
+fn skip_unsupported(setting: &str, result: Result<(), Error>) -> Result<bool, Error> {
+    match result {
+        Ok(())                        => Ok(true),
+        Err(Error::Unsupported(what)) => {
+            warn!("Couldn't restore {setting}, not supported by device: {what}");
+            Ok(false)
+        }
+        Err(e)                        => Err(e),
+    }
 }
 
-async fn capabilities(ipc: &Ipc, device: &dyn BluetoothDevice) -> Result<(), Error> {
+async fn capabilities(daemon: &Daemon, device: &dyn BluetoothDevice, goal: &Arc<AtomicU32>, previous: &LastBattery, messengers: &mut Vec<JoinHandle<()>>) -> Result<(), Error> {
     debug!("Detecting device capabilities...");
 
+    let Daemon { ipc, store, .. } = daemon;
+    let address = device.address();
+
     if let Some(battery) = device.battery() {
         let battery_stream = battery.battery_stream().await?;
-        ipc.add_messenger(battery_stream.map(Message::from));
+        messengers.push(ipc.add_messenger(address, record(store, address, battery_events(previous, battery_stream))));
     }
 
     if let Some(steps) = device.steps() {
         let steps_stream = steps.notify_steps().await?;
-        ipc.add_messenger(steps_stream.map(Message::Steps));
+        messengers.push(ipc.add_messenger(address, record(store, address, reach_goal(store, address, goal, steps_stream)?)));
     }
 
     if let Some(heartrate) = device.heartrate() {
         let heartrate_stream = heartrate.nofity_heartrate().await?;
-        ipc.add_messenger(heartrate_stream.map(Message::Heartrate));
+        messengers.push(ipc.add_messenger(address, record(store, address, heartrate_stream
+            .filter(|m| future::ready(m.contact != Some(false)))
+            .map(Message::from))));
     }
 
     Ok(())
 }
 
-async fn command(device: &dyn BluetoothDevice, commands: &mut impl DerefMut<Target = Receiver<Command>>, tx: &mut mpsc::Sender<Message>) -> Result<(), Error> {
-    while let Some(command) = commands.recv().await {
-        match command {
-            Command::Steps((action, n)) => {
-                if let Some(steps) = device.steps() {
-                    match action {
-                        CommandAction::Get => {
-                            let message = steps.steps().await.map(Message::Steps)?;
-                            tx.try_send(message).unwrap();
-                        }
-                        CommandAction::Set => {
-                            steps.set_steps(n.unwrap()).await?
-                        }
-                    }
+// Steps followed by a message when they cross the goal, once a day
+fn reach_goal<S>(store: &Arc<Store>, address: Address, goal: &Arc<AtomicU32>, steps: S) -> Result<impl Stream<Item = Message>, Error>
+where
+    S: Stream<Item = u32>
+{
+    let store = store.clone();
+    let goal  = goal.clone();
+
+    let mut reached = store.goal_days(address)?.last().copied();
+
+    Ok(steps.flat_map(move |steps| {
+        let mut messages = vec![Message::Steps(steps)];
+
+        let today = Local::now().date_naive();
+        let goal  = goal.load(Ordering::Relaxed);
+
+        if goal > 0 && steps >= goal && reached != Some(today) {
+            reached = Some(today);
+
+            // Goal is recorded with this message, streak doesn't include today yet
+            let streak = store.streak(address, today).unwrap_or(0) + 1;
+            messages.push(Message::GoalReached((goal, streak)));
+        }
+
+        stream::iter(messages)
+    }))
+}
+
+// Level and the whole battery info, followed by a message when it gets low, starts charging or is charged.
+// Previous report is kept across connections, so reconnecting doesn't announce the same change again.
+fn battery_events<S>(previous: &LastBattery, battery: S) -> impl Stream<Item = Message>
+where
+    S: Stream<Item = BatteryInfo>
+{
+    let previous = previous.clone();
+
+    battery.flat_map(move |info| {
+        let mut messages = vec![Message::from(info), Message::BatteryInfo(info)];
+
+        let mut previous = previous.lock().unwrap();
+        if let Some(event) = BatteryEvent::between(previous.as_ref(), &info) {
+            messages.push(Message::BatteryEvent((event, info.level)));
+        }
+
+        *previous = Some(info);
+
+        stream::iter(messages)
+    })
+}
+
+// Pattern playing alongside everything else, until it's over or another one cuts it short
+fn play(device: &dyn BluetoothDevice, pattern: Pattern) -> Result<BoxFuture<'_, Result<(), Error>>, Error> {
+    let alert = device.alert().ok_or_else(|| unsupported("alert pattern"))?;
+
+    Ok(Box::pin(async move { alert.pattern(&pattern).await }))
+}
+
+// Cuts the playing pattern short in favour of another, it might have left the motor running
+async fn replay<'a>(playback: &mut Option<BoxFuture<'a, Result<(), Error>>>, device: &'a dyn BluetoothDevice, pattern: Pattern) -> Result<(), Error> {
+    let next = play(device, pattern)?;
+
+    if playback.take().is_some() {
+        if let Some(alert) = device.alert() {
+            alert.stop().await?;
+        }
+    }
+
+    *playback = Some(next);
+
+    Ok(())
+}
+
+async fn synchronize(clock: &(dyn Clock + Sync + Send)) -> Result<(), Error> {
+    let drift = drift(&clock.datetime().await?);
+
+    debug!("Clock drifted by {drift}s, synchronizing");
+
+    clock.set_datetime(&Utc::now().into()).await
+}
+
+// Seconds the device is ahead of the host
+fn drift(dt: &DateTime) -> i64 {
+    (**dt - Utc::now()).num_seconds()
+}
+
+fn record<S>(store: &Arc<Store>, address: Address, messages: S) -> impl Stream<Item = Message>
+where
+    S: Stream<Item = Message>
+{
+    let store = store.clone();
+
+    messages.inspect(move |message| store.record(address, message))
+}
+
+// Messages answering the command, what mustn't wait for the answer is sent through `tx` or broadcast along the way
+async fn command(daemon: &Daemon, device: &dyn BluetoothDevice, goal: &AtomicU32, command: Command, tx: &mut mpsc::Sender<Message>) -> Result<Vec<Message>, Error> {
+    let Daemon { config, state, store, .. } = daemon;
+
+    let mut replies = Vec::new();
+
+    match command {
+        Command::Steps((action, n)) => {
+            let steps = device.steps().ok_or_else(|| unsupported("steps"))?;
+
+            match action {
+                CommandAction::Get => {
+                    let message = steps.steps().await.map(Message::Steps)?;
+                    replies.push(message);
                 }
+                CommandAction::Set => {
+                    steps.set_steps(n.unwrap()).await?
+                }
+            }
+        }
+        Command::StepGoal((action, n)) => {
+            let steps = device.steps().ok_or_else(|| unsupported("step goal"))?;
+            let address = device.address();
+
+            if let (CommandAction::Set, Some(n)) = (action, n) {
+                steps.set_goal(n).await?;
+                goal.store(n, Ordering::Relaxed);
+
+                let mut state = state.lock().await;
+                state.device(address).goal = Some(n);
+                state.save()?;
             }
-            Command::Battery => {
-                if let Some(battery) = device.battery() {
-                    let message = battery.battery().await.map(Message::from)?;
-                    tx.try_send(message).unwrap();
+
+            let streak = store.streak(address, Local::now().date_naive())?;
+            replies.push(Message::StepGoal((goal.load(Ordering::Relaxed), streak)));
+        }
+        Command::Alert(level) => {
+            device.alert().ok_or_else(|| unsupported("alert"))?.alert(level).await?;
+        }
+        Command::Battery => {
+            let info = device.battery().ok_or_else(|| unsupported("battery"))?.battery().await?;
+            replies.extend([Message::from(info), Message::BatteryInfo(info)]);
+        }
+        Command::Heartrate => {
+            device.heartrate().ok_or_else(|| unsupported("heart rate"))?.heartrate().await?;
+        }
+        Command::HeartrateContinuous(enable) => {
+            device.heartrate().ok_or_else(|| unsupported("heart rate"))?.heartrate_continuous(enable).await?;
+        }
+        Command::Activity => {
+            let activity = device.activity().ok_or_else(|| unsupported("activity"))?;
+            let mut samples = Vec::new();
+
+            if let Err(e) = activity.sync_activity(&mut |block| samples.extend(block)).await {
+                // Whatever the device has forgotten already is still worth keeping
+                for sample in samples {
+                    tx.send(Message::Activity(sample)).await.unwrap();
                 }
+
+                return Err(e)
             }
-            Command::Heartrate => {
-                if let Some(heartrate) = device.heartrate() {
-                    heartrate.heartrate().await?;
+
+            replies.extend(samples.into_iter().map(Message::Activity));
+        }
+        Command::Alarm((action, slot)) => {
+            let alarm = device.alarm().ok_or_else(|| unsupported("alarm"))?;
+
+            match (action, slot) {
+                (CommandAction::Set, Some(slot)) => {
+                    alarm.set_alarm(&slot).await?;
+                    state.lock().await.set_alarm(device.address(), slot)?;
+                }
+                _ => {
+                    let slots = state.lock().await.alarms(device.address(), &config.alarms);
+
+                    for slot in slots {
+                        replies.push(Message::Alarm(slot));
+                    }
                 }
             }
-            Command::HeartrateContinuous(enable) => {
-                if let Some(heartrate) = device.heartrate() {
-                    heartrate.heartrate_continuous(enable).await?;
+        }
+        Command::AlarmEnable((n, enabled)) => {
+            let alarm = device.alarm().ok_or_else(|| unsupported("alarm"))?;
+            let slots = state.lock().await.alarms(device.address(), &config.alarms);
+
+            // Time of a slot that was never set is unknown
+            let Some(mut slot) = slots.into_iter().find(|slot| slot.slot == n) else {
+                return Err(Error::Unsupported(format!("alarm slot {n} that was never set")))
+            };
+
+            slot.enabled = enabled;
+            alarm.set_alarm(&slot).await?;
+            state.lock().await.set_alarm(device.address(), slot)?;
+        }
+        Command::DateTime((action, dt)) => {
+            let clock = device.clock().ok_or_else(|| unsupported("clock"))?;
+
+            if let (CommandAction::Set, Some(dt)) = (action, dt) {
+                clock.set_datetime(&dt).await?;
+            }
+
+            let dt = clock.datetime().await?;
+            replies.push(Message::DateTime((dt, drift(&dt))));
+        }
+        Command::Profile((action, profile)) => {
+            let address = device.address();
+
+            if let (CommandAction::Set, Some(profile)) = (action, profile) {
+                let user = {
+                    let mut state = state.lock().await;
+                    state.set_profile(address, profile, config.alias.as_deref())?;
+                    user(config, &mut state, address)?
+                };
+
+                if let Some(p) = device.profile() {
+                    p.set_user(&user).await?;
                 }
             }
-            Command::Name => {
-                device.deref().name().await?;
+
+            let User { alias, bio, .. } = user(config, &mut *state.lock().await, address)?;
+            replies.push(Message::Profile(Profile { alias, bio }));
+        }
+        Command::WearLocation((action, location)) => {
+            let wear = device.wear().ok_or_else(|| unsupported("wear location"))?;
+            let address = device.address();
+
+            match (action, location) {
+                (CommandAction::Set, Some(location)) => {
+                    wear.set_wear_location(location).await?;
+
+                    let mut state = state.lock().await;
+                    state.device(address).wear = Some(location);
+                    state.save()?;
+                }
+                _ => {
+                    let location = state.lock().await.device(address).wear;
+
+                    if let Some(location) = location.or(config.wear) {
+                        replies.push(Message::WearLocation(location));
+                    }
+                }
             }
-            _ => {
-                warn!("Command is not implemented {:?}", command);
+        }
+        Command::Firmware((path, dry)) => {
+            let firmware = device.firmware().ok_or_else(|| unsupported("firmware update"))?;
+            let image = fs::read(&path)?;
+
+            let parts = firmware.inspect(&image)?;
+
+            replies.extend(parts.into_iter().map(Message::FirmwarePart));
+
+            if !dry {
+                // Progress can't wait for a messenger, every report has to get through
+                let address = device.address();
+                firmware.flash(&image, &mut |p| daemon.ipc.broadcast(address, Message::FirmwareProgress(p))).await?;
             }
         }
+        Command::Name => {
+            replies.push(Message::Name(device.info().await?.name));
+        }
+        Command::Info => {
+            replies.push(Message::Info(device.info().await?));
+        }
+        _ => {
+            return Err(Error::Unsupported(format!("{:?}", command)))
+        }
     }
 
-    Ok(())
+    Ok(replies)
+}
+
+// Device lacks the capability a command needs
+fn unsupported(capability: &str) -> Error {
+    Error::Unsupported(capability.to_owned())
 }
 
 // Bluetooth Things
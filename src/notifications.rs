@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use dbus::{arg::PropMap, channel::{Channel, MatchingReceiver}, message::MatchRule, nonblock::{Proxy, SyncConnection}};
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use tokio::sync::broadcast;
+
+use crate::{Error, devices::capabilities::alert::{AlertLevel, Pattern}};
+
+const INTERFACE: &str = "org.freedesktop.Notifications";
+const TIMEOUT:   Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    pub bus:   Option<String>, // address, session bus if not set
+    pub level: AlertLevel,
+    pub rules: Vec<Rule>,
+}
+
+// Every condition that is set has to match
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub app:     Option<String>,
+    pub urgency: Option<Urgency>, // at least
+    #[serde(default, deserialize_with = "regex")]
+    pub summary: Option<Regex>,
+    #[serde(default, deserialize_with = "regex")]
+    pub body:    Option<Regex>,
+    pub level:   Option<AlertLevel>,
+    pub pattern: Option<Pattern>, // takes precedence over level
+}
+
+// How the device should get user's attention
+#[derive(Debug, Clone, Copy)]
+pub enum Signal {
+    Level(AlertLevel),
+    Pattern(Pattern),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+#[derive(Debug)]
+struct Notification {
+    app:     String,
+    summary: String,
+    body:    String,
+    urgency: Urgency,
+}
+
+impl Notifications {
+    /// Signal of the first matching rule, or default level for every notification when there are no rules.
+    fn signal(&self, notification: &Notification) -> Option<Signal> {
+        if self.rules.is_empty() {
+            return Some(Signal::Level(self.level))
+        }
+
+        self.rules.iter()
+            .find(|rule| rule.matches(notification))
+            .map(|rule| match rule.pattern {
+                Some(pattern) => Signal::Pattern(pattern),
+                None          => Signal::Level(rule.level.unwrap_or(self.level)),
+            })
+    }
+}
+
+impl Rule {
+    fn matches(&self, n: &Notification) -> bool {
+        self.app.as_ref().is_none_or(|app| *app == n.app)
+            && self.urgency.is_none_or(|urgency| n.urgency >= urgency)
+            && self.summary.as_ref().is_none_or(|r| r.is_match(&n.summary))
+            && self.body.as_ref().is_none_or(|r| r.is_match(&n.body))
+    }
+}
+
+impl Notification {
+    // app_name, replaces_id, app_icon, summary, body, actions, hints, expire_timeout
+    fn read(message: &dbus::Message) -> Option<Self> {
+        let (app, _, _, summary, body, _, hints, _): (String, u32, String, String, String, Vec<String>, PropMap, i32) = message.read_all().ok()?;
+
+        let urgency = match hints.get("urgency").and_then(|v| v.0.as_u64()) {
+            Some(0) => Urgency::Low,
+            Some(2) => Urgency::Critical,
+            _       => Urgency::Normal,
+        };
+
+        Some(Self { app, summary, body, urgency })
+    }
+}
+
+/// Eavesdrops on notifications sent to the bus and forwards signals of the matching ones.
+pub async fn listen(config: &Notifications, tx: broadcast::Sender<Signal>) -> Result<(), Error> {
+    let (resource, connection) = match &config.bus {
+        Some(address) => {
+            let mut channel = Channel::open_private(address)?;
+            channel.register()?;
+
+            dbus_tokio::connection::from_channel::<SyncConnection>(channel)?
+        }
+        None => dbus_tokio::connection::new_session_sync()?,
+    };
+
+    tokio::spawn(async move {
+        let e = resource.await;
+        warn!("Lost connection to D-Bus: {e}");
+    });
+
+    let rule = MatchRule::new_method_call()
+        .with_interface(INTERFACE)
+        .with_member("Notify");
+
+    // Monitors receive messages addressed to others, but can't send anything after
+    Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT, connection.clone())
+        .method_call::<(), _, _, _>("org.freedesktop.DBus.Monitoring", "BecomeMonitor", (vec![rule.match_str()], 0u32))
+        .await?;
+
+    debug!("Listening for desktop notifications...");
+
+    let config = config.clone();
+
+    connection.start_receive(rule, Box::new(move |message, _| {
+        if let Some(notification) = Notification::read(&message) {
+            match config.signal(&notification) {
+                Some(signal) => {
+                    debug!("Forwarding {:?}", notification);
+
+                    if tx.send(signal).is_err() {
+                        warn!("Dropped notification from {}, no device to alert", notification.app);
+                    }
+                }
+                None => debug!("Ignoring {:?}", notification),
+            }
+        }
+
+        true
+    }));
+
+    Ok(())
+}
+
+fn regex<'de, D>(d: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let s = String::deserialize(d)?;
+
+    Regex::new(&s).map(Some).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    fn notification(app: &str, summary: &str, body: &str, urgency: Urgency) -> Notification {
+        Notification { app: app.to_owned(), summary: summary.to_owned(), body: body.to_owned(), urgency }
+    }
+
+    fn config(s: &str) -> Notifications {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn rule_matches() {
+        let rule = &config(r#"
+            [[rules]]
+            app     = "Telegram"
+            urgency = "normal"
+            summary = "^Alice"
+            body    = "(?i)lunch"
+        "#).rules[0];
+
+        assert!(rule.matches(&notification("Telegram", "Alice", "Lunch?", Urgency::Normal)));
+        assert!(rule.matches(&notification("Telegram", "Alice", "lunch", Urgency::Critical)));
+        assert!(!rule.matches(&notification("Signal", "Alice", "lunch", Urgency::Normal)));
+        assert!(!rule.matches(&notification("Telegram", "Alice", "lunch", Urgency::Low)));
+        assert!(!rule.matches(&notification("Telegram", "Bob, Alice", "lunch", Urgency::Normal)));
+        assert!(!rule.matches(&notification("Telegram", "Alice", "dinner", Urgency::Normal)));
+    }
+
+    #[test]
+    fn signal() {
+        let anything = notification("Telegram", "Alice", "lunch", Urgency::Normal);
+        assert!(matches!(config("level = \"high\"").signal(&anything), Some(Signal::Level(AlertLevel::High))));
+
+        let config = config(r#"
+            [[rules]]
+            app     = "Mail"
+            pattern = { repeat = 2 }
+
+            [[rules]]
+            urgency = "critical"
+            level   = "high"
+
+            [[rules]]
+            app     = "Telegram"
+        "#);
+
+        assert!(matches!(config.signal(&notification("Mail", "", "", Urgency::Critical)), Some(Signal::Pattern(Pattern { repeat: 2, .. }))));
+        assert!(matches!(config.signal(&notification("Chat", "", "", Urgency::Critical)), Some(Signal::Level(AlertLevel::High))));
+        assert!(matches!(config.signal(&anything), Some(Signal::Level(AlertLevel::Mild))));
+        assert!(config.signal(&notification("Chat", "", "", Urgency::Normal)).is_none());
+    }
+
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs dbus-daemon"]
+    async fn listen_to_bus() {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let _bus = Bus(daemon);
+
+        let config = Notifications { bus: Some(address.trim().to_owned()), ..config("level = \"high\"") };
+        let (tx, mut rx) = broadcast::channel(1);
+
+        listen(&config, tx).await.unwrap();
+
+        let mut channel = Channel::open_private(address.trim()).unwrap();
+        channel.register().unwrap();
+
+        let notify = dbus::Message::new_method_call(INTERFACE, "/org/freedesktop/Notifications", INTERFACE, "Notify")
+            .unwrap()
+            .append3("Telegram", 0u32, "")
+            .append3("Alice", "lunch?", Vec::<String>::new())
+            .append2(PropMap::new(), -1i32);
+
+        channel.send(notify).unwrap();
+        channel.flush();
+
+        let signal = tokio::time::timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        assert!(matches!(signal, Signal::Level(AlertLevel::High)));
+    }
+}
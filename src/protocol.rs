@@ -0,0 +1,281 @@
+use std::io::{self, Cursor};
+
+use bluer::Address;
+use byteorder::{LittleEndian, ReadBytesExt};
+use log::warn;
+
+use crate::{Error, command::{self, Request}, ipc::Message, json};
+
+pub const HELLO:   &[u8; 5] = b"INOLI";
+pub const VERSION: u8       = 2;
+
+// Nothing a client has to say takes this long, anything bigger is garbage
+const MAX_FRAME: usize = 64 * 1024;
+
+const EVENT:    u8 = 0;
+const RESPONSE: u8 = 1;
+const FAILURE:  u8 = 2;
+
+/// Wire format spoken with a client, decided by the first bytes it sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// `CMD` and `MSG` prefixed bytes, commands are never answered directly
+    Legacy,
+    /// Length prefixed frames, every request is answered by its id
+    Framed,
+    /// JSON object per line, every request is answered by its id
+    Json,
+}
+
+/// Pulls requests out of `buf`, leaving incomplete ones for later.
+/// Switches `protocol` when a client greets, the greeting to send back is returned.
+pub fn decode(protocol: &mut Protocol, buf: &mut Vec<u8>, requests: &mut Vec<(Option<u32>, Result<Request, Error>)>) -> Result<Option<Vec<u8>>, Error> {
+    let mut greeting = None;
+
+    // Greeting might be split between reads as well
+    if *protocol == Protocol::Legacy && buf.len() <= HELLO.len() && HELLO.starts_with(buf) {
+        return Ok(None)
+    }
+
+    // Nothing binary starts with a brace
+    if *protocol == Protocol::Legacy && buf.first() == Some(&b'{') {
+        *protocol = Protocol::Json;
+    }
+
+    if *protocol == Protocol::Legacy && buf.starts_with(HELLO) {
+        let Some(&version) = buf.get(HELLO.len()) else {
+            return Ok(None)
+        };
+
+        buf.drain(..HELLO.len() + 1);
+
+        // Anything older than framing is the legacy protocol, anything newer is answered with what we speak
+        if version >= 2 {
+            *protocol = Protocol::Framed;
+        }
+
+        greeting = Some([HELLO.as_slice(), &[version.min(VERSION)]].concat());
+    }
+
+    match protocol {
+        Protocol::Legacy => legacy(buf, requests),
+        Protocol::Framed => framed(buf, requests)?,
+        Protocol::Json   => lines(buf, requests)?,
+    }
+
+    Ok(greeting)
+}
+
+fn legacy(buf: &mut Vec<u8>, requests: &mut Vec<(Option<u32>, Result<Request, Error>)>) {
+    use command::MAGIC;
+
+    while let Some(i) = buf.windows(MAGIC.len()).position(|window| window == MAGIC) {
+        let mut r = Cursor::new(&buf[i + MAGIC.len()..]);
+
+        match Request::read_untagged(&mut r) {
+            Ok(request) => {
+                let end = i + MAGIC.len() + r.position() as usize;
+
+                requests.push((None, Ok(request)));
+                buf.drain(..end);
+            }
+            // Rest of the command hasn't arrived yet
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(e) => {
+                warn!("Couldn't parse incoming message: {e}");
+                buf.drain(..i + MAGIC.len());
+            }
+        }
+    }
+
+    // Magic might be split between reads
+    let keep = buf.len().min(MAGIC.len() - 1);
+    buf.drain(..buf.len() - keep);
+}
+
+// length: u32, of everything that follows
+// id:     u32,
+// command
+fn framed(buf: &mut Vec<u8>, requests: &mut Vec<(Option<u32>, Result<Request, Error>)>) -> Result<(), Error> {
+    while buf.len() >= 4 {
+        let length = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+
+        if !(4..=MAX_FRAME).contains(&length) {
+            return Err(Error::Length { expected: MAX_FRAME, actual: length })
+        }
+
+        if buf.len() < 4 + length {
+            return Ok(())
+        }
+
+        let frame: Vec<u8> = buf.drain(..4 + length).skip(4).collect();
+        let mut r = Cursor::new(frame.as_slice());
+
+        let id = r.read_u32::<LittleEndian>()?;
+
+        requests.push((Some(id), Request::read(&mut r)));
+    }
+
+    Ok(())
+}
+
+fn lines(buf: &mut Vec<u8>, requests: &mut Vec<(Option<u32>, Result<Request, Error>)>) -> Result<(), Error> {
+    while let Some(i) = buf.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buf.drain(..=i).collect();
+
+        if line.trim_ascii().is_empty() {
+            continue
+        }
+
+        let (id, request) = json::request(&line);
+        requests.push((Some(id), request));
+    }
+
+    if buf.len() > MAX_FRAME {
+        return Err(Error::Length { expected: MAX_FRAME, actual: buf.len() })
+    }
+
+    Ok(())
+}
+
+pub fn event(protocol: Protocol, device: Address, message: &Message) -> Vec<u8> {
+    match protocol {
+        Protocol::Legacy => message.to_le_bytes(),
+        Protocol::Framed => frame(EVENT, &message.encode(device)),
+        Protocol::Json   => line(json::message(device, message)),
+    }
+}
+
+pub fn response(protocol: Protocol, id: u32, messages: &[(Address, Message)]) -> Vec<u8> {
+    match protocol {
+        Protocol::Json => line(json::response(id, messages)),
+        _              => framed_response(id, messages),
+    }
+}
+
+pub fn failure(protocol: Protocol, id: u32, error: &Error) -> Vec<u8> {
+    match protocol {
+        Protocol::Json => line(json::failure(id, error)),
+        _              => framed_failure(id, error),
+    }
+}
+
+// id:       u32,
+// count:    u16,
+// messages: u16 length followed by the message, as many as counted
+fn framed_response(id: u32, messages: &[(Address, Message)]) -> Vec<u8> {
+    let mut body = id.to_le_bytes().to_vec();
+    body.extend_from_slice(&(messages.len().min(u16::MAX as usize) as u16).to_le_bytes());
+
+    for (device, message) in messages.iter().take(u16::MAX as usize) {
+        let message = message.encode(*device);
+
+        body.extend_from_slice(&(message.len() as u16).to_le_bytes());
+        body.extend_from_slice(&message);
+    }
+
+    frame(RESPONSE, &body)
+}
+
+// id:     u32,
+// code:   u8,
+// reason: u16 length followed by as many bytes of UTF-8
+fn framed_failure(id: u32, error: &Error) -> Vec<u8> {
+    let reason = error.to_string();
+    let reason = &reason.as_bytes()[..reason.len().min(u16::MAX as usize)];
+
+    let mut body = id.to_le_bytes().to_vec();
+    body.push(error.code());
+    body.extend_from_slice(&(reason.len() as u16).to_le_bytes());
+    body.extend_from_slice(reason);
+
+    frame(FAILURE, &body)
+}
+
+// length: u32, of everything that follows
+// kind:   u8,
+// body
+fn frame(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut vec = ((body.len() + 1) as u32).to_le_bytes().to_vec();
+    vec.push(kind);
+    vec.extend_from_slice(body);
+    vec
+}
+
+fn line(value: serde_json::Value) -> Vec<u8> {
+    let mut vec = value.to_string().into_bytes();
+    vec.push(b'\n');
+    vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+
+    type Requests = Vec<(Option<u32>, Result<Request, Error>)>;
+
+    #[test]
+    fn legacy_split() {
+        let (mut protocol, mut buf, mut requests) = (Protocol::Legacy, Vec::new(), Requests::new());
+
+        // Garbage is skipped and a command split between reads waits for the rest
+        for chunk in [b"xxCM".as_slice(), b"D\x53", b"\x00CMD\x50\x01\x10", b"\x00\x00\x00"] {
+            buf.extend_from_slice(chunk);
+            decode(&mut protocol, &mut buf, &mut requests).unwrap();
+        }
+
+        assert_eq!(protocol, Protocol::Legacy);
+        assert!(buf.is_empty());
+        assert!(matches!(requests.as_slice(), [
+            (None, Ok(Request { device: None, command: Command::Battery, .. })),
+            (None, Ok(Request { device: None, command: Command::Steps((_, Some(16))), .. })),
+        ]));
+    }
+
+    #[test]
+    fn framed() {
+        let (mut protocol, mut buf, mut requests) = (Protocol::Legacy, Vec::new(), Requests::new());
+
+        buf.extend_from_slice(b"INO");
+        assert_eq!(decode(&mut protocol, &mut buf, &mut requests).unwrap(), None);
+
+        buf.extend_from_slice(b"LI\x05");
+        assert_eq!(decode(&mut protocol, &mut buf, &mut requests).unwrap().as_deref(), Some(b"INOLI\x02".as_slice()));
+        assert_eq!(protocol, Protocol::Framed);
+
+        // Battery of a named device, then half of a frame
+        let device = [1, 2, 3, 4, 5, 6];
+        let frame = [&12u32.to_le_bytes()[..], &7u32.to_le_bytes(), &[83], &device, &[0]].concat();
+        buf.extend_from_slice(&frame);
+        buf.extend_from_slice(&frame[..6]);
+        decode(&mut protocol, &mut buf, &mut requests).unwrap();
+
+        assert_eq!(buf.len(), 6);
+        assert!(matches!(requests.as_slice(), [(Some(7), Ok(Request { device: Some(d), command: Command::Battery, .. }))] if d.0 == device));
+
+        buf.clear();
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode(&mut protocol, &mut buf, &mut requests), Err(Error::Length { .. })));
+    }
+
+    #[test]
+    fn old_greeting() {
+        let (mut protocol, mut buf, mut requests) = (Protocol::Legacy, b"INOLI\x01".to_vec(), Requests::new());
+
+        assert_eq!(decode(&mut protocol, &mut buf, &mut requests).unwrap().as_deref(), Some(b"INOLI\x01".as_slice()));
+        assert_eq!(protocol, Protocol::Legacy);
+    }
+
+    #[test]
+    fn json_lines() {
+        let (mut protocol, mut buf, mut requests) = (Protocol::Legacy, Vec::new(), Requests::new());
+
+        buf.extend_from_slice(b"{\"id\": 3, \"command\": \"battery\"}\n\n{\"id\": 4, \"comm");
+        decode(&mut protocol, &mut buf, &mut requests).unwrap();
+
+        assert_eq!(protocol, Protocol::Json);
+        assert_eq!(buf, b"{\"id\": 4, \"comm");
+        assert!(matches!(requests.as_slice(), [(Some(3), Ok(Request { command: Command::Battery, .. }))]));
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+
+use bluer::Address;
+use tokio::sync::mpsc;
+
+use crate::Error;
+use crate::command::Request;
+use crate::ipc::ConnectionState;
+
+struct Entry {
+    address:  Address,
+    state:    ConnectionState,
+    commands: mpsc::Sender<Request>,
+}
+
+/// Devices managed by the daemon in the order they were found, each one served by its own task.
+#[derive(Default)]
+pub struct Registry {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Registry {
+    /// Returns `false` when the device is already managed.
+    pub fn insert(&self, address: Address, commands: mpsc::Sender<Request>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.iter().any(|e| e.address == address) {
+            return false
+        }
+
+        entries.push(Entry { address, state: ConnectionState::Connecting, commands });
+
+        true
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        self.entries.lock().unwrap().iter().any(|e| e.address == address)
+    }
+
+    pub fn set_state(&self, address: Address, state: ConnectionState) {
+        if let Some(e) = self.entries.lock().unwrap().iter_mut().find(|e| e.address == address) {
+            e.state = state;
+        }
+    }
+
+    pub fn list(&self) -> Vec<(Address, ConnectionState)> {
+        self.entries.lock().unwrap().iter().map(|e| (e.address, e.state)).collect()
+    }
+
+    /// Commands of `device`, or of the first ready device when none is given.
+    /// Devices that aren't ready wouldn't get to them until they are, so they are refused.
+    pub fn commands(&self, device: Option<Address>) -> Result<mpsc::Sender<Request>, Error> {
+        let entries = self.entries.lock().unwrap();
+
+        let entry = match device {
+            Some(address) => entries.iter().find(|e| e.address == address),
+            None          => entries.iter().find(|e| e.state == ConnectionState::Ready).or(entries.first()),
+        };
+
+        match entry {
+            Some(e) if e.state == ConnectionState::Ready => Ok(e.commands.clone()),
+            Some(_)                                      => Err(Error::NotConnected),
+            None                                         => Err(Error::NoDevice),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_devices_only() {
+        let registry = Registry::default();
+        let (a, b) = (Address::new([1; 6]), Address::new([2; 6]));
+
+        assert!(matches!(registry.commands(None), Err(Error::NoDevice)));
+
+        registry.insert(a, mpsc::channel(1).0);
+        registry.insert(b, mpsc::channel(1).0);
+
+        assert!(matches!(registry.commands(None), Err(Error::NotConnected)));
+        assert!(matches!(registry.commands(Some(Address::any())), Err(Error::NoDevice)));
+
+        registry.set_state(b, ConnectionState::Ready);
+
+        assert!(registry.commands(None).is_ok());
+        assert!(registry.commands(Some(b)).is_ok());
+        assert!(matches!(registry.commands(Some(a)), Err(Error::NotConnected)));
+    }
+}
@@ -0,0 +1,132 @@
+use std::{path::{Path, PathBuf}, fs, env, collections::HashMap};
+
+use bluer::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, bio::{Bio, Profile}, devices::{WearLocation, capabilities::alarm::AlarmSlot}};
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(skip)]
+    path:    PathBuf,
+    devices: HashMap<Address, DeviceState>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceState {
+    pub identity: Option<Identity>,
+    pub alarms:   Vec<AlarmSlot>,
+    pub wear:     Option<WearLocation>,
+    pub bio:      Option<Bio>,
+    pub goal:     Option<u32>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub id:    u32,
+    pub alias: String,
+}
+
+impl Identity {
+    // Alias from configuration, or whoever runs the daemon
+    fn generate(alias: Option<&str>) -> Self {
+        let alias = alias.map(str::to_owned)
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| "inoli".to_owned());
+
+        Self { id: rand::random(), alias }
+    }
+}
+
+impl State {
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>
+    {
+        let path = path.as_ref();
+
+        let mut state: State = match fs::read(path) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        state.path = path.to_owned();
+
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, &self.path).map_err(Into::into)
+    }
+
+    pub fn device(&mut self, address: Address) -> &mut DeviceState {
+        self.devices.entry(address).or_default()
+    }
+
+    /// Alarms set over IPC, with slots that were never set falling back to `defaults`.
+    pub fn alarms(&mut self, address: Address, defaults: &[AlarmSlot]) -> Vec<AlarmSlot> {
+        let alarms = &self.device(address).alarms;
+
+        let mut merged: Vec<AlarmSlot> = defaults.iter()
+            .filter(|d| !alarms.iter().any(|a| a.slot == d.slot))
+            .chain(alarms.iter())
+            .copied()
+            .collect();
+
+        merged.sort_by_key(|a| a.slot);
+        merged
+    }
+
+    pub fn set_alarm(&mut self, address: Address, alarm: AlarmSlot) -> Result<(), Error> {
+        let alarms = &mut self.device(address).alarms;
+
+        alarms.retain(|a| a.slot != alarm.slot);
+        alarms.push(alarm);
+
+        self.save()
+    }
+
+    /// Empty alias keeps the current one, `alias` seeds the identity if there is none yet.
+    pub fn set_profile(&mut self, address: Address, profile: Profile, alias: Option<&str>) -> Result<(), Error> {
+        let mut identity = self.identity(address, alias)?;
+        if !profile.alias.is_empty() {
+            identity.alias = profile.alias;
+        }
+
+        let device = self.device(address);
+        device.identity = Some(identity);
+        device.bio      = Some(profile.bio);
+
+        self.save()
+    }
+
+    /// Identity the daemon introduces itself with to the device, generated on first use with `alias` if given.
+    pub fn identity(&mut self, address: Address, alias: Option<&str>) -> Result<Identity, Error> {
+        if let Some(identity) = &self.device(address).identity {
+            return Ok(identity.clone())
+        }
+
+        let identity = Identity::generate(alias);
+        self.device(address).identity = Some(identity.clone());
+        self.save()?;
+
+        Ok(identity)
+    }
+}
+
+pub fn default_path() -> PathBuf {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+        .unwrap_or_default()
+        .join("inoli")
+        .join("state.json")
+}
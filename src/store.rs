@@ -0,0 +1,328 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}, fs::{self, File, OpenOptions}, env, io::{BufReader, Read, Write, ErrorKind}};
+
+use bluer::Address;
+use chrono::{Local, NaiveDate, TimeZone, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ipc::Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Battery,
+    Steps,
+    Heartrate,
+    Activity,
+    Goal,
+}
+
+// timestamp: i64,
+// device:    [u8; 6],
+// value:     u32,
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub metric:    Metric,
+    pub timestamp: i64,
+    pub device:    Address,
+    pub value:     u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregate {
+    pub metric: Metric,
+    pub from:   i64,
+    pub to:     i64,
+    pub count:  u32,
+    pub min:    u32,
+    pub max:    u32,
+    pub sum:    u64,
+}
+
+// Every metric is kept in its own append-only file of fixed size records,
+// samples are not ordered, since activity history arrives long after it was recorded.
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Metric {
+    fn file_name(self) -> &'static str {
+        match self {
+            Metric::Battery   => "battery",
+            Metric::Steps     => "steps",
+            Metric::Heartrate => "heartrate",
+            Metric::Activity  => "activity",
+            Metric::Goal      => "goal",
+        }
+    }
+
+    // Activity is stored whole, but only steps make sense to aggregate
+    fn scalar(self, value: u32) -> u32 {
+        match self {
+            Metric::Activity => value.to_le_bytes()[2] as u32,
+            _                => value,
+        }
+    }
+}
+
+impl TryFrom<u8> for Metric {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Metric::Battery),
+            1 => Ok(Metric::Steps),
+            2 => Ok(Metric::Heartrate),
+            3 => Ok(Metric::Activity),
+            4 => Ok(Metric::Goal),
+            _ => Err(Error::Parse { expected: "0,1,2,3,4", position: 0, actual: value })
+        }
+    }
+}
+
+impl From<Metric> for u8 {
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::Battery   => 0,
+            Metric::Steps     => 1,
+            Metric::Heartrate => 2,
+            Metric::Activity  => 3,
+            Metric::Goal      => 4,
+        }
+    }
+}
+
+impl Sample {
+    const SIZE: usize = 18;
+
+    fn from_message(device: Address, message: &Message) -> Option<Self> {
+        let timestamp = Utc::now().timestamp();
+
+        let (metric, timestamp, value) = match *message {
+            Message::Battery(v)   => (Metric::Battery,   timestamp, v as u32),
+            Message::Steps(v)     => (Metric::Steps,     timestamp, v),
+            Message::Heartrate(v) => (Metric::Heartrate, timestamp, v as u32),
+            Message::Activity(s)  => {
+                let value = u32::from_le_bytes([s.category, s.intensity, s.steps, s.heartrate.unwrap_or(0)]);
+                (Metric::Activity, s.timestamp.timestamp(), value)
+            }
+            Message::GoalReached((goal, _)) => (Metric::Goal, timestamp, goal),
+            _ => return None,
+        };
+
+        Some(Self { metric, timestamp, device, value })
+    }
+
+    fn to_le_bytes(self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        b[8..14].copy_from_slice(&self.device.0);
+        b[14..18].copy_from_slice(&self.value.to_le_bytes());
+        b
+    }
+
+    fn from_le_bytes(metric: Metric, b: &[u8; Self::SIZE]) -> Self {
+        Self {
+            metric,
+            timestamp: i64::from_le_bytes(b[0..8].try_into().unwrap()),
+            device:    Address::new(b[8..14].try_into().unwrap()),
+            value:     u32::from_le_bytes(b[14..18].try_into().unwrap()),
+        }
+    }
+}
+
+impl Store {
+    pub fn open<P>(dir: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>
+    {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self { dir: dir.as_ref().to_owned() })
+    }
+
+    fn path(&self, metric: Metric) -> PathBuf {
+        self.dir.join(metric.file_name())
+    }
+
+    pub fn append(&self, sample: Sample) -> Result<(), Error> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(sample.metric))?
+            .write_all(&sample.to_le_bytes())
+            .map_err(Into::into)
+    }
+
+    /// Records a sample for every message that carries a metric, everything else is ignored.
+    pub fn record(&self, device: Address, message: &Message) {
+        if let Some(sample) = Sample::from_message(device, message) {
+            if let Err(e) = self.append(sample) {
+                warn!("Couldn't record {:?} sample: {}", sample.metric, e);
+            }
+        }
+    }
+
+    pub fn range(&self, metric: Metric, device: Option<Address>, from: i64, to: i64) -> Result<Vec<Sample>, Error> {
+        if from >= to {
+            return Err(Error::InvalidRange { from, to })
+        }
+
+        let file = match File::open(self.path(metric)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut r = BufReader::new(file);
+        let mut b = [0; Sample::SIZE];
+        let mut samples = Vec::new();
+
+        loop {
+            match r.read_exact(&mut b) {
+                Ok(()) => {},
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            let sample = Sample::from_le_bytes(metric, &b);
+
+            if (from..to).contains(&sample.timestamp) && device.is_none_or(|d| d == sample.device) {
+                samples.push(sample);
+            }
+        }
+
+        samples.sort_by_key(|s| s.timestamp);
+
+        Ok(samples)
+    }
+
+    /// Aggregates samples in range, split into buckets of `bucket` seconds, or a single bucket if it's 0.
+    pub fn aggregate(&self, metric: Metric, device: Option<Address>, from: i64, to: i64, bucket: u32) -> Result<Vec<Aggregate>, Error> {
+        if from >= to {
+            return Err(Error::InvalidRange { from, to })
+        }
+
+        // Range can span all of i64, bounds of a bucket always fall within it
+        let bucket = if bucket == 0 { to as i128 - from as i128 } else { bucket as i128 };
+        let bounds = |timestamp: i64| {
+            let start = from as i128 + (timestamp as i128 - from as i128) / bucket * bucket;
+            (start as i64, (start + bucket).min(to as i128) as i64)
+        };
+
+        let mut aggregates: Vec<Aggregate> = Vec::new();
+
+        for sample in self.range(metric, device, from, to)? {
+            let (start, end) = bounds(sample.timestamp);
+            let value = metric.scalar(sample.value);
+
+            match aggregates.last_mut() {
+                Some(a) if a.from == start => {
+                    a.count += 1;
+                    a.min    = a.min.min(value);
+                    a.max    = a.max.max(value);
+                    a.sum   += value as u64;
+                }
+                _ => aggregates.push(Aggregate {
+                    metric,
+                    from:  start,
+                    to:    end,
+                    count: 1,
+                    min:   value,
+                    max:   value,
+                    sum:   value as u64,
+                }),
+            }
+        }
+
+        Ok(aggregates)
+    }
+
+    /// Local days on which device reached its step goal.
+    pub fn goal_days(&self, device: Address) -> Result<BTreeSet<NaiveDate>, Error> {
+        let days = self.range(Metric::Goal, Some(device), i64::MIN, i64::MAX)?
+            .into_iter()
+            .filter_map(|s| Local.timestamp_opt(s.timestamp, 0).single())
+            .map(|dt| dt.date_naive())
+            .collect();
+
+        Ok(days)
+    }
+
+    /// Consecutive days the goal was reached on, until today is over streak continues from yesterday.
+    pub fn streak(&self, device: Address, today: NaiveDate) -> Result<u32, Error> {
+        let days = self.goal_days(device)?;
+
+        let mut day = if days.contains(&today) { Some(today) } else { today.pred_opt() };
+        let mut streak = 0;
+
+        while let Some(d) = day.filter(|d| days.contains(d)) {
+            streak += 1;
+            day = d.pred_opt();
+        }
+
+        Ok(streak)
+    }
+}
+
+pub fn default_path() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+        .unwrap_or_default()
+        .join("inoli")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> Store {
+        let dir = env::temp_dir().join(format!("inoli-store-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        Store::open(dir).unwrap()
+    }
+
+    fn sample(timestamp: i64, value: u32) -> Sample {
+        Sample { metric: Metric::Steps, timestamp, device: Address::any(), value }
+    }
+
+    #[test]
+    fn aggregate_buckets() {
+        let store = store("buckets");
+        for (timestamp, value) in [(0, 1), (5, 3), (10, 7), (25, 2)] {
+            store.append(sample(timestamp, value)).unwrap();
+        }
+
+        let aggregates = store.aggregate(Metric::Steps, None, 0, 30, 10).unwrap();
+        let buckets: Vec<_> = aggregates.iter().map(|a| (a.from, a.to, a.count, a.min, a.max, a.sum)).collect();
+
+        assert_eq!(buckets, [(0, 10, 2, 1, 3, 4), (10, 20, 1, 7, 7, 7), (20, 30, 1, 2, 2, 2)]);
+    }
+
+    #[test]
+    fn aggregate_whole_range() {
+        let store = store("range");
+        store.append(sample(i64::MIN, 1)).unwrap();
+        store.append(sample(i64::MAX - 1, 2)).unwrap();
+
+        let aggregates = store.aggregate(Metric::Steps, None, i64::MIN, i64::MAX, 0).unwrap();
+
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!((aggregates[0].from, aggregates[0].to, aggregates[0].sum), (i64::MIN, i64::MAX, 3));
+
+        let aggregates = store.aggregate(Metric::Steps, None, -1, i64::MAX, 1).unwrap();
+        assert_eq!(aggregates[0].from, i64::MAX - 1);
+    }
+
+    #[test]
+    fn aggregate_empty_range() {
+        let store = store("empty");
+
+        assert!(matches!(store.aggregate(Metric::Steps, None, 10, 10, 0), Err(Error::InvalidRange { from: 10, to: 10 })));
+        assert!(matches!(store.aggregate(Metric::Steps, None, 10, -1, 0), Err(Error::InvalidRange { .. })));
+        assert!(matches!(store.range(Metric::Steps, None, 10, 10), Err(Error::InvalidRange { .. })));
+        assert!(store.range(Metric::Steps, None, 10, 11).unwrap().is_empty());
+    }
+}
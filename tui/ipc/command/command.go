@@ -8,10 +8,12 @@ import (
 type Command byte
 
 const (
+	Devices             Command = 10
 	Battery             Command = 83
 	Heartrate           Command = 139
 	HeartrateContinuous Command = 173
 	Name                Command = 244
+	Info                Command = 245
 	Steps               Command = 80
 )
 
@@ -22,6 +24,7 @@ const (
 	Set Action = 1
 )
 
+// Commands without a greeting go to the first connected device
 func WriteCommand(conn net.Conn, cmd Command, action Action, payload []byte) error {
 	var header = []byte { 'C', 'M', 'D', byte(cmd), byte(action) }
 	var buf = append(header, payload...)
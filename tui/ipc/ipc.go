@@ -6,10 +6,18 @@ import (
 	"inoli-tui/ipc/command"
 )
 
+func RequestDevices(conn net.Conn) error {
+	return command.WriteCommand(conn, command.Devices, command.Get, nil)
+}
+
 func RequestName(conn net.Conn) error {
 	return command.WriteCommand(conn, command.Name, command.Get, nil)
 }
 
+func RequestInfo(conn net.Conn) error {
+	return command.WriteCommand(conn, command.Info, command.Get, nil)
+}
+
 func RequestBattery(conn net.Conn) error {
 	return command.WriteCommand(conn, command.Battery, command.Get, nil)
 }
@@ -3,7 +3,17 @@ package message
 type Message byte
 
 const (
-	Battery   Message = 11
-	Heartrate Message = 12
-	Steps     Message = 13
+	Battery     Message = 11
+	Heartrate   Message = 12
+	Steps       Message = 13
+	Devices     Message = 26
+	Info        Message = 27
+	Name        Message = 28
+	BatteryInfo Message = 29
 )
+
+// Connection state of a device as reported in Devices
+const Ready byte = 3
+
+// Battery status as reported in BatteryInfo
+const Charging byte = 2
@@ -3,10 +3,12 @@ package main
 import (
 	"bytes"
 	"encoding/binary"
+	"io"
 	"strings"
 	"net"
 	"fmt"
 	"os"
+	"path/filepath"
 
 	"inoli-tui/ipc"
 	"inoli-tui/ipc/message"
@@ -35,14 +37,21 @@ type keymap struct {
 }
 
 type batteryMsg   struct { value byte   }
+type chargingMsg  struct { value bool   }
 type heartrateMsg struct { value byte   }
 type stepsMsg     struct { value uint32 }
+type devicesMsg   struct { ready, total byte }
+type nameMsg      struct { value string }
+type infoMsg      struct { name string; firmware version }
 
 type model struct {
 	title     string
 	battery   byte
+	charging  bool
 	heartrate byte
 	steps     uint32
+	ready     byte
+	devices   byte
 	firmware  version
 
 	help      help.Model
@@ -57,10 +66,18 @@ func (m model) Update(msg tea.Msg) (tea.Model, tea.Cmd) {
 	switch msg := msg.(type) {
 	case batteryMsg:
 		m.battery = msg.value
+	case chargingMsg:
+		m.charging = msg.value
 	case heartrateMsg:
 		m.heartrate = msg.value
 	case stepsMsg:
 		m.steps = msg.value
+	case devicesMsg:
+		m.ready, m.devices = msg.ready, msg.total
+	case nameMsg:
+		m.title = msg.value
+	case infoMsg:
+		m.title, m.firmware = msg.name, msg.firmware
 	case tea.KeyMsg:
 		switch {
 		case key.Matches(msg, m.keymap.refresh):
@@ -95,24 +112,47 @@ func (m model) helpView() string {
 func (m model) View() string {
 	var s = strings.Builder{}
 
-	var battery = fmt.Sprintf("%s: %d%%\n", battery, m.battery)
+	var charging = ""
+	if m.charging { charging = " charging" }
+
+	var battery = fmt.Sprintf("%s: %d%%%s\n", battery, m.battery, charging)
 	var heartrate = fmt.Sprintf("%s: %d BPM\n", heart, m.heartrate)
-	var steps = fmt.Sprintf("%s: %d", human, m.steps)
+	var steps = fmt.Sprintf("%s: %d\n", human, m.steps)
+	var devices = fmt.Sprintf("%s: %d/%d ready", watch, m.ready, m.devices)
+	var title = fmt.Sprintf("%s\n", m.title)
+	var firmware = fmt.Sprintf("\nfirmware %d.%d.%d.%d", m.firmware.major, m.firmware.minor, m.firmware.maintenance, m.firmware.build)
 
-	//s.WriteString(title)
+	s.WriteString(title)
 	s.WriteString(battery)
 	s.WriteString(heartrate)
 	s.WriteString(steps)
-	//s.WriteString(firmware)
+	s.WriteString(devices)
+	s.WriteString(firmware)
 
 	return style.Render(s.String()) + m.helpView()
 }
 
+func socketPath() string {
+	if len(os.Args) > 1 {
+		return os.Args[1]
+	}
+
+	var dir = os.Getenv("XDG_RUNTIME_DIR")
+	if dir == "" {
+		dir = os.TempDir()
+	}
+
+	return filepath.Join(dir, "inoli.sock")
+}
+
 func connect(socket string) (net.Conn, error) {
 	var conn, err = net.Dial("unix", socket)
 	if err != nil { return nil, err }
 
-	ipc.RequestName(conn)
+	ipc.RequestDevices(conn)
+	if err != nil { return nil, err }
+
+	ipc.RequestInfo(conn)
 	if err != nil { return nil, err }
 
 	ipc.RequestBattery(conn)
@@ -128,43 +168,110 @@ func connect(socket string) (net.Conn, error) {
 }
 
 func handleMessages(conn net.Conn, p *tea.Program) error {
-	var buf = make([]byte, 16)
-	var magic = []byte { 'M', 'S', 'G' }
+	var chunk = make([]byte, 512)
+	var buf []byte
 
 	for {
-		var _, err = conn.Read(buf)
+		var n, err = conn.Read(chunk)
 		if err != nil { return err }
 
-		if !bytes.Equal(buf[0:len(magic)], magic) {
-			continue
+		// One read may hold several messages, the last of them cut short
+		buf = append(buf, chunk[:n]...)
+
+		for {
+			var start = bytes.Index(buf, magic)
+			if start < 0 {
+				// Magic might be split between reads
+				if len(buf) >= len(magic) { buf = buf[len(buf)-len(magic)+1:] }
+				break
+			}
+
+			var r = bytes.NewReader(buf[start+len(magic):])
+
+			msgs, err := decodeMessage(r)
+			if err != nil {
+				// Rest of the message hasn't arrived yet
+				buf = buf[start:]
+				break
+			}
+
+			for _, msg := range msgs { p.Send(msg) }
+
+			buf = buf[len(buf)-r.Len():]
 		}
+	}
+}
+
+var magic = []byte { 'M', 'S', 'G' }
+
+// Whatever the model cares about in a single message, an error means it isn't whole yet
+func decodeMessage(r *bytes.Reader) ([]tea.Msg, error) {
+	kind, err := r.ReadByte()
+	if err != nil { return nil, err }
 
-		var r = bytes.NewReader(buf[len(magic):])
-
-		_kind, err := r.ReadByte()
-		if err != nil { panic("couldn't read message payload - EOF") }
-
-		var kind = message.Message(_kind)
-
-		switch kind {
-		case message.Battery:
-			value, err := r.ReadByte()
-			if err != nil { panic("couldn't read message payload - EOF") }
-			p.Send(batteryMsg { value })
-		case message.Heartrate:
-			value, err := r.ReadByte()
-			if err != nil { panic("couldn't read message payload - EOF") }
-			p.Send(heartrateMsg { value })
-		case message.Steps:
-			var value uint32
-			binary.Read(r, binary.LittleEndian, &value)
-			p.Send(stepsMsg { value })
+	switch message.Message(kind) {
+	case message.Battery:
+		value, err := r.ReadByte()
+		if err != nil { return nil, err }
+		return []tea.Msg { batteryMsg { value } }, nil
+	case message.BatteryInfo:
+		var b [12]byte
+		if _, err := io.ReadFull(r, b[:]); err != nil { return nil, err }
+
+		// level and status, last charge and cycles aren't shown
+		return []tea.Msg { batteryMsg { b[0] }, chargingMsg { b[1] == message.Charging } }, nil
+	case message.Heartrate:
+		value, err := r.ReadByte()
+		if err != nil { return nil, err }
+		return []tea.Msg { heartrateMsg { value } }, nil
+	case message.Steps:
+		var value uint32
+		if err := binary.Read(r, binary.LittleEndian, &value); err != nil { return nil, err }
+		return []tea.Msg { stepsMsg { value } }, nil
+	case message.Devices:
+		total, err := r.ReadByte()
+		if err != nil { return nil, err }
+
+		var ready byte
+		for i := byte(0); i < total; i++ {
+			var entry [7]byte
+			if _, err := io.ReadFull(r, entry[:]); err != nil { return nil, err }
+			if entry[6] == message.Ready { ready++ }
 		}
+
+		return []tea.Msg { devicesMsg { ready, total } }, nil
+	case message.Name:
+		name, err := readString(r)
+		if err != nil { return nil, err }
+		return []tea.Msg { nameMsg { name } }, nil
+	case message.Info:
+		var b [13]byte
+		if _, err := io.ReadFull(r, b[:]); err != nil { return nil, err }
+
+		name, err := readString(r)
+		if err != nil { return nil, err }
+
+		// hardware, profile, firmware and heart rate firmware, versions are stored backwards
+		var firmware = version { int(b[8]), int(b[7]), int(b[6]), int(b[5]) }
+		return []tea.Msg { infoMsg { name, firmware } }, nil
 	}
+
+	// Anything else is skipped up to the next magic
+	return nil, nil
+}
+
+func readString(r *bytes.Reader) (string, error) {
+	n, err := r.ReadByte()
+	if err != nil { return "", err }
+
+	var b = make([]byte, n)
+	if _, err := io.ReadFull(r, b); err != nil { return "", err }
+
+	return string(b), nil
 }
 
 func main() {
-	var conn, err = connect("../socket")
+	var conn, err = connect(socketPath())
 
 	model := model {
 		title:     "",